@@ -0,0 +1,463 @@
+use crate::fs::{FileOps, replace_with, temp_sibling};
+use crate::group::{KeepPolicy, Resolver};
+use crate::journal::Journal;
+use crate::trash;
+use anyhow::Result;
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use log::{debug, error, trace, warn};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::fs::canonicalize;
+use tokio::io::AsyncWriteExt;
+
+/// Name of the manifest kept at the root of a quarantine directory
+pub const QUARANTINE_MANIFEST: &str = "manifest.tsv";
+
+/// What happens to a duplicate once its survivor is known
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Action {
+    /// Delete the duplicate
+    #[default]
+    Delete,
+    /// Replace the duplicate with a hard link to its survivor
+    Hardlink,
+    /// Share the survivor's extents on copy-on-write filesystems like btrfs and XFS
+    Reflink,
+    /// Replace the duplicate with a symbolic link to its survivor
+    Symlink { relative: bool },
+    /// Move the duplicate to the freedesktop.org trash can of its filesystem
+    Trash,
+    /// Move the duplicate under a quarantine directory, mirroring its path under its root
+    Quarantine(PathBuf),
+}
+
+const ACTIONS: [&str; 7] = [
+    "delete",
+    "hardlink",
+    "reflink",
+    "symlink",
+    "symlink=absolute",
+    "symlink=relative",
+    "trash",
+];
+
+impl Action {
+    /// Whether the action needs the survivor to be present on this machine
+    pub fn needs_survivor(&self) -> bool {
+        !matches!(self, Action::Delete | Action::Trash | Action::Quarantine(_))
+    }
+
+    /// Whether the action links duplicates to survivors on the same filesystem
+    pub fn needs_same_filesystem(&self) -> bool {
+        matches!(self, Action::Hardlink | Action::Reflink)
+    }
+
+    pub fn past_tense(&self) -> &'static str {
+        match self {
+            Action::Delete => "deleted",
+            Action::Hardlink => "hard linked",
+            Action::Reflink => "reflinked",
+            Action::Symlink { .. } => "symlinked",
+            Action::Trash => "trashed",
+            Action::Quarantine(_) => "quarantined",
+        }
+    }
+
+    /// Value parser listing every accepted spelling in help and completions
+    pub fn parser() -> impl TypedValueParser<Value = Action> {
+        PossibleValuesParser::new(ACTIONS).map(|s| s.parse().expect("validated by clap"))
+    }
+}
+
+impl FromStr for Action {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "delete" => Ok(Action::Delete),
+            "hardlink" => Ok(Action::Hardlink),
+            "reflink" => Ok(Action::Reflink),
+            "symlink" | "symlink=absolute" => Ok(Action::Symlink { relative: false }),
+            "symlink=relative" => Ok(Action::Symlink { relative: true }),
+            "trash" => Ok(Action::Trash),
+            _ => anyhow::bail!("Unknown action {s}, expected one of {}", ACTIONS.join(", ")),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Action::Delete => "delete",
+            Action::Hardlink => "hardlink",
+            Action::Reflink => "reflink",
+            Action::Symlink { relative: false } => "symlink=absolute",
+            Action::Symlink { relative: true } => "symlink=relative",
+            Action::Trash => "trash",
+            Action::Quarantine(_) => "quarantine",
+        })
+    }
+}
+
+/// A file found to duplicate another, as handed to [`Executor::apply`]
+#[derive(Debug)]
+pub struct Duplicate<'a> {
+    pub path: &'a Path,
+    /// Tree `path` was found under
+    pub root: &'a Path,
+    /// Copy kept in place of `path`. Absent when it lives on another machine
+    pub survivor: Option<&'a Path>,
+    /// Copy on another machine `path` duplicates, as named by the input file of remote mode
+    pub reference: Option<&'a str>,
+    pub size: usize,
+    pub chksum: &'a str,
+}
+
+/// What became of a duplicate handed to [`Executor::apply`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The action was applied, or would have been on a dry run
+    Applied,
+    /// The duplicate differs from its survivor despite their checksums, either because of a
+    /// hash collision or because one of them changed since hashing. It is left alone
+    Mismatch,
+    /// The duplicate was reached through a symbolic link, and acting on it would change what
+    /// the link points to rather than the tree being walked. It is left alone
+    Linked,
+    /// The duplicate already is its survivor, through a hard link or a symlink, so there was
+    /// nothing to do
+    Unchanged,
+    /// The action failed on the duplicate, as logged by whoever got the error from
+    /// [`Executor::apply`]
+    Failed,
+}
+
+/// Counts reported at the end of a run
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Summary {
+    pub processed: usize,
+    pub duplicates: usize,
+    pub mismatched: usize,
+    pub linked: usize,
+    pub failed: usize,
+}
+
+impl Summary {
+    pub fn record(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Applied => self.duplicates += 1,
+            Outcome::Mismatch => self.mismatched += 1,
+            Outcome::Linked => self.linked += 1,
+            Outcome::Unchanged => {}
+            Outcome::Failed => self.failed += 1,
+        }
+    }
+}
+
+/// Carries out the chosen action on duplicates, or just reports them on a dry run
+#[derive(Debug)]
+pub struct Executor {
+    commit: bool,
+    keep: KeepPolicy,
+    action: Action,
+    verify: bool,
+    journal: Option<Journal>,
+}
+
+impl Executor {
+    /// Duplicates are compared byte for byte with their survivor before acting on them if
+    /// `verify` is set, and by default on commit. Committed actions are recorded to `journal`
+    pub fn new(
+        commit: bool,
+        keep: KeepPolicy,
+        action: Action,
+        verify: Option<bool>,
+        journal: Option<Journal>,
+    ) -> Self {
+        Self {
+            commit,
+            keep,
+            action,
+            verify: verify.unwrap_or(commit),
+            journal,
+        }
+    }
+
+    pub fn commit(&self) -> bool {
+        self.commit
+    }
+
+    pub fn keep(&self) -> KeepPolicy {
+        self.keep
+    }
+
+    pub fn action(&self) -> &Action {
+        &self.action
+    }
+
+    /// Builds a resolver picking survivors among files under `roots`
+    pub fn resolver(&self, roots: Vec<PathBuf>) -> Resolver {
+        Resolver::new(self.keep, roots, self.action.needs_same_filesystem())
+    }
+
+    pub fn verify(&self) -> bool {
+        self.verify
+    }
+
+    /// Acts on `duplicate`. Its survivor is required by actions creating links to it, and is
+    /// what the duplicate is verified against. Duplicates without one cannot be verified
+    pub async fn apply(&self, duplicate: &Duplicate<'_>) -> Result<Outcome> {
+        let path = duplicate.path;
+        let need_survivor = || {
+            duplicate.survivor.ok_or_else(|| {
+                anyhow::anyhow!("{} needs a survivor for {}", self.action, path.display())
+            })
+        };
+
+        if through_symlink(duplicate).await? {
+            debug!(
+                "{}: reached through a symlink, leaving its target alone",
+                path.display()
+            );
+            return Ok(Outcome::Linked);
+        }
+
+        // The survivor under another name, whether a hard link or a symlink to the duplicate.
+        // Acting on it frees nothing, may destroy the only copy, and undoing it would break a
+        // link this run did not make, so it is neither journaled nor counted
+        if let Some(survivor) = duplicate.survivor
+            && path.same_file(survivor).await?
+        {
+            trace!("{}: same file as {}", path.display(), survivor.display());
+            return Ok(Outcome::Unchanged);
+        }
+
+        if let Some(survivor) = duplicate.survivor
+            && self.verify
+            && !path.dup_of(survivor).await?
+        {
+            warn!(
+                "{} differs from {} despite sharing chksum {}, leaving it alone",
+                path.display(),
+                survivor.display(),
+                duplicate.chksum
+            );
+            return Ok(Outcome::Mismatch);
+        }
+
+        // Resolved before acting, as moves leave nothing behind to resolve
+        let original = std::path::absolute(path)?;
+        let moved_to = match &self.action {
+            Action::Delete => path.remove_file(self.commit).await.map(|_| None),
+            Action::Hardlink => path
+                .hardlink_to(need_survivor()?, self.commit)
+                .await
+                .map(|_| None),
+            Action::Reflink => path
+                .reflink_to(need_survivor()?, self.commit)
+                .await
+                .map(|_| None),
+            Action::Symlink { relative } => path
+                .symlink_to(need_survivor()?, *relative, self.commit)
+                .await
+                .map(|_| None),
+            Action::Trash if !self.commit => {
+                trace!("{}: candidate for trashing", path.display());
+                Ok(None)
+            }
+            Action::Trash => trash::trash(path).await.map(Some),
+            Action::Quarantine(dir) => self.quarantine(duplicate, dir).await,
+        }?;
+
+        // The action cannot be taken back at this point, so its outcome stands regardless
+        if let Some(journal) = &self.journal
+            && self.commit
+            && let Err(e) = journal
+                .record(&self.action, duplicate, original, moved_to)
+                .await
+        {
+            error!(
+                "{} was {} but could not be journaled, so undo cannot restore it. Error: {e}",
+                path.display(),
+                self.action.past_tense()
+            );
+        }
+        Ok(Outcome::Applied)
+    }
+
+    /// Returns where the duplicate was moved to, on commit. Each root is mirrored at its own
+    /// absolute path under `dir`, so files of several roots cannot collide
+    async fn quarantine(&self, duplicate: &Duplicate<'_>, dir: &Path) -> Result<Option<PathBuf>> {
+        let relative = duplicate.path.strip_prefix(duplicate.root)?;
+        let root = canonicalize(duplicate.root).await?;
+        let dest = dir.join(root.strip_prefix("/")?).join(relative);
+        if !self.commit {
+            trace!(
+                "{}: candidate for quarantine at {}",
+                duplicate.path.display(),
+                dest.display()
+            );
+            return Ok(None);
+        }
+
+        // Absolute paths keep the manifest meaningful wherever it is read from
+        let original = canonicalize(duplicate.path).await?;
+        let survivor = match duplicate.survivor {
+            Some(survivor) => Some(canonicalize(survivor).await?),
+            None => None,
+        };
+        duplicate.path.move_to(&dest).await?;
+
+        let manifest = dir.join(QUARANTINE_MANIFEST);
+        let new_manifest = !manifest.exists();
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&manifest)
+            .await?;
+        let mut record = String::new();
+        if new_manifest {
+            record.push_str("original\tsurvivor\tsize\tchksum\troot\n");
+        }
+        let survivor = match (&survivor, duplicate.reference) {
+            (Some(survivor), _) => survivor.display().to_string(),
+            (None, Some(reference)) => reference.to_string(),
+            (None, None) => "-".to_string(),
+        };
+        record.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            original.display(),
+            survivor,
+            duplicate.size,
+            duplicate.chksum,
+            root.display()
+        ));
+        file.write_all(record.as_bytes()).await?;
+        Ok(Some(std::path::absolute(dest)?))
+    }
+}
+
+/// Drops the last row of the quarantine `manifest` listing `original` with `chksum`, once it
+/// has been moved back
+pub async fn unlist(manifest: &Path, original: &Path, chksum: &str) -> Result<()> {
+    let original = canonicalize(original).await?.display().to_string();
+    let contents = tokio::fs::read_to_string(manifest).await?;
+    let mut rows = contents.lines().collect::<Vec<_>>();
+    let listed = rows.iter().rposition(|row| {
+        let mut columns = row.split('\t');
+        columns.next() == Some(original.as_str()) && columns.nth(2) == Some(chksum)
+    });
+    let Some(listed) = listed else {
+        anyhow::bail!("{original} is not listed");
+    };
+    rows.remove(listed);
+
+    let temp = temp_sibling(manifest)?;
+    let mut contents = rows.join("\n");
+    contents.push('\n');
+    if let Err(e) = tokio::fs::write(&temp, contents).await {
+        let _ = tokio::fs::remove_file(&temp).await;
+        return Err(e.into());
+    }
+    replace_with(&temp, manifest).await
+}
+
+/// Whether `duplicate` is a symlink, or lies in a directory reached through one below its root
+async fn through_symlink(duplicate: &Duplicate<'_>) -> Result<bool> {
+    let path = duplicate.path;
+    if tokio::fs::symlink_metadata(path).await?.is_symlink() {
+        return Ok(true);
+    }
+    // Duplicates found under no root have nothing to be compared with
+    let Ok(relative) = path.strip_prefix(duplicate.root) else {
+        return Ok(false);
+    };
+    let (Some(parent), Some(relative_parent)) = (path.parent(), relative.parent()) else {
+        return Ok(false);
+    };
+    if duplicate.root.as_os_str().is_empty() {
+        return Ok(false);
+    }
+    // Links above the root were followed by whoever named it, so only those below it count
+    let expected = canonicalize(duplicate.root).await?.join(relative_parent);
+    Ok(canonicalize(parent).await? != expected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_listed_action_parses_back_to_its_name() {
+        for name in ACTIONS {
+            let action: Action = name.parse().unwrap();
+            match name {
+                "symlink" => assert_eq!(action, Action::Symlink { relative: false }),
+                _ => assert_eq!(action.to_string(), name),
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_actions_are_refused() {
+        let err = "move".parse::<Action>().unwrap_err();
+        assert!(err.to_string().starts_with("Unknown action move"), "{err}");
+        assert!("quarantine".parse::<Action>().is_err());
+    }
+
+    /// Deletes `path`, a copy of `survivor` found under `root`
+    async fn delete(root: &Path, path: &Path, survivor: &Path) -> Outcome {
+        let duplicate = Duplicate {
+            path,
+            root,
+            survivor: Some(survivor),
+            reference: None,
+            size: 4,
+            chksum: "0123",
+        };
+        let exec = Executor::new(true, KeepPolicy::default(), Action::Delete, None, None);
+        exec.apply(&duplicate).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn duplicates_reached_through_symlinks_are_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let (root, other) = (dir.path().join("root"), dir.path().join("other"));
+        std::fs::create_dir_all(root.join("d")).unwrap();
+        std::fs::create_dir_all(&other).unwrap();
+        let survivor = root.join("d/survivor");
+        for file in [&survivor, &other.join("copy")] {
+            std::fs::write(file, "same").unwrap();
+        }
+        std::os::unix::fs::symlink(&other, root.join("linked")).unwrap();
+        std::os::unix::fs::symlink(&survivor, root.join("d/link")).unwrap();
+
+        let through_dir = root.join("linked/copy");
+        assert_eq!(
+            delete(&root, &through_dir, &survivor).await,
+            Outcome::Linked
+        );
+        assert!(other.join("copy").exists());
+        let link = root.join("d/link");
+        assert_eq!(delete(&root, &link, &survivor).await, Outcome::Linked);
+        assert!(link.exists());
+
+        // Links above the root were followed by whoever named it
+        let linked_root = root.join("linked");
+        let outcome = delete(&linked_root, &through_dir, &survivor).await;
+        assert_eq!(outcome, Outcome::Applied);
+        assert!(!other.join("copy").exists());
+    }
+
+    #[tokio::test]
+    async fn duplicates_that_are_their_survivor_are_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let (survivor, linked) = (dir.path().join("survivor"), dir.path().join("linked"));
+        std::fs::write(&survivor, "same").unwrap();
+        std::fs::hard_link(&survivor, &linked).unwrap();
+        let outcome = delete(dir.path(), &linked, &survivor).await;
+        assert_eq!(outcome, Outcome::Unchanged);
+        assert!(linked.exists());
+    }
+}
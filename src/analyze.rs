@@ -1,105 +1,345 @@
-use crate::hasher::HashFile;
-use crate::fs::DirOps;
-use crate::fs::FileOps;
+use crate::fs::{DirOps, Walker};
+use crate::hasher::{HashAlgo, cached_chksum};
+use crate::inventory::{
+    Format, Header, Inventory, InventoryWriter, Layout, Record, hostname, write_inventory,
+};
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use clap::Args;
 use futures::{StreamExt, stream};
-use log::{debug, info};
-use std::fmt::Write;
+use log::{debug, info, trace, warn};
 use std::{
     collections::{HashMap, HashSet},
     path::{Path, PathBuf},
 };
 use tokio::fs::canonicalize;
-use tokio::io::{AsyncWriteExt, BufWriter};
 
 #[derive(Args, Debug)]
 #[command(arg_required_else_help = true)]
 /// Analyzes path and writes data to file to be used elsewhere
 pub struct Analyze {
-    /// File to write the output of hash analysis
+    /// File to write the output of hash analysis, or `-` for stdout
     #[arg(short, long, default_value = "dedup.out")]
     pub output_file: PathBuf,
 
     /// Local Path containing files that need to be checked for duplicates
     #[arg(short, long, default_value = ".")]
     pub local_path: PathBuf,
+
+    /// Records every file with its path, size and modification time, rather than only the
+    /// checksums found for each size, so remote mode can name the reference copies
+    #[arg(short, long)]
+    pub per_file: bool,
+
+    /// Encoding of the output. Remote mode tells them apart on its own
+    #[arg(short, long, value_enum, default_value_t)]
+    pub format: Format,
+
+    /// Writes records as files are hashed rather than once all are, unsorted and followed by
+    /// their counts, so the output can be piped into remote mode as it is produced
+    #[arg(short, long)]
+    pub stream: bool,
+
+    /// Refreshes this per-file analysis file in place, in its own format, hashing only the
+    /// files added or modified since by size and modification time, and dropping deleted ones
+    #[arg(short, long, conflicts_with_all = ["output_file", "local_path", "per_file", "format"])]
+    pub update: Option<PathBuf>,
 }
 
 impl Analyze {
-    pub async fn analyze(&self) -> Result<()> {
+    /// Hashes with `requested`, or else the algorithm of the analysis file being updated, or
+    /// else the default one
+    pub async fn analyze(&self, requested: Option<HashAlgo>, walker: &Walker) -> Result<()> {
+        // Files recorded by the analysis being updated, by path
+        let mut known = HashMap::new();
+        let (local_path, output_file, format, per_file, algo) = match &self.update {
+            Some(update) => {
+                let previous = Inventory::read_all(update, requested).await?;
+                let Some(header) = previous
+                    .header
+                    .clone()
+                    .filter(|h| h.layout == Layout::Files)
+                else {
+                    anyhow::bail!(
+                        "{} holds no per-file records, so it cannot be updated",
+                        update.display()
+                    );
+                };
+                anyhow::ensure!(
+                    header.host == hostname(),
+                    "{} describes a tree on {}, so it can only be updated there",
+                    update.display(),
+                    header.host
+                );
+                let (format, algo) = (previous.format, previous.algo);
+                for record in previous.into_records() {
+                    if let Some(path) = record.path.clone() {
+                        known.insert(path, record);
+                    }
+                }
+                (header.root, update.clone(), format, true, algo)
+            }
+            None => (
+                self.local_path.clone(),
+                self.output_file.clone(),
+                self.format,
+                self.per_file,
+                requested.unwrap_or_default(),
+            ),
+        };
+
+        if !local_path.exists() {
+            anyhow::bail!("Local path not found - {}", local_path.display());
+        }
+        if per_file && format == Format::Binary {
+            anyhow::bail!("The binary format holds no per-file records");
+        }
+        if self.stream && format == Format::Binary {
+            anyhow::bail!("The binary format cannot be streamed, as its records are sorted");
+        }
+
+        let root = canonicalize(&local_path).await?;
         debug!(
             "Starting analysis at {}, and writing out to {}",
-            canonicalize(&self.local_path).await.unwrap().display(),
-            self.output_file.display()
+            root.display(),
+            output_file.display()
         );
 
-        if !self.local_path.exists() {
-            anyhow::bail!("Local path not found - {}", self.local_path.display());
-        }
-
-        let mut file_map = HashMap::new();
+        let layout = if per_file {
+            Layout::Files
+        } else {
+            Layout::Sizes
+        };
+        let mut writer = match self.stream {
+            true => {
+                let header = Header {
+                    streamed: true,
+                    ..Header::new(layout, algo, root.clone(), 0, 0)
+                };
+                Some(InventoryWriter::create(&header, format, &output_file).await?)
+            }
+            false => None,
+        };
+        let mut chksums = HashSet::new();
+        let mut records = Vec::new();
         let mut num_analyzed = 0;
+        let (mut num_known, mut num_unchanged, mut num_unreadable) = (0, 0, 0);
 
-        let entries = self.local_path.walkdir();
+        let entries = local_path.walkdir(walker);
+        let (known, local_path) = (&known, &local_path);
 
         let mut stream = stream::iter(entries)
-            .map(move |file_path| {
-                async move {
-                    debug!("Start analyzing file: {}", file_path.display());
-                    let file_path_clone = file_path.clone();
-                    async {
-                        let (size, chksum) = tokio::task::spawn_blocking(move || {
-                            file_path_clone.chksum()
-                        }).await??;
-                        debug!("Finished analyzing file: {}", file_path.display());
-                        Ok::<_, Box<dyn std::error::Error>>((size, chksum))
+            .map(move |file_path| async move {
+                debug!("Start analyzing file: {}", file_path.display());
+                let path = file_path.strip_prefix(local_path).map(Path::to_path_buf);
+                let file_path_clone = file_path.clone();
+                let analyzed = async {
+                    let md = tokio::fs::metadata(&file_path_clone).await?;
+                    let mtime: DateTime<Utc> = md.modified()?.into();
+                    let path = path.clone()?;
+                    if let Some(record) = known.get(&path)
+                        && record.size == Some(md.len() as usize)
+                        && record.mtime == Some(mtime)
+                    {
+                        trace!("{}: unchanged since the last analysis", file_path.display());
+                        return Ok((record.clone(), Origin::Unchanged));
                     }
-                    .await
-                    .ok()
+
+                    let (size, chksum) = cached_chksum(file_path_clone, algo).await?;
+                    debug!("Finished analyzing file: {}", file_path.display());
+                    let record = Record {
+                        size: Some(size),
+                        mtime: Some(mtime),
+                        chksum,
+                        path: Some(path),
+                    };
+                    Ok::<_, anyhow::Error>((record, Origin::Hashed))
+                }
+                .await;
+                match analyzed {
+                    Ok(analyzed) => Some(analyzed),
+                    // Counting them as deleted would drop them from the updated analysis
+                    Err(e) => match path.ok().and_then(|path| known.get(&path)) {
+                        Some(record) => {
+                            warn!(
+                                "Error analyzing file {}, keeping its previous record: {e}",
+                                file_path.display()
+                            );
+                            Some((record.clone(), Origin::Unreadable))
+                        }
+                        None => {
+                            warn!("Error analyzing file {}: {e}", file_path.display());
+                            None
+                        }
+                    },
                 }
             })
             .buffer_unordered(num_cpus::get() * 2);
 
-        while let Some(Some((size, chksum))) = stream.next().await {
+        while let Some(entry) = stream.next().await {
+            let Some((record, origin)) = entry else {
+                continue;
+            };
             num_analyzed += 1;
-            file_map
-                .entry(size)
-                .or_insert(HashSet::new())
-                .insert(chksum);
+            match origin {
+                Origin::Hashed => {}
+                Origin::Unchanged => num_unchanged += 1,
+                Origin::Unreadable => num_unreadable += 1,
+            }
+            num_known += usize::from(
+                record
+                    .path
+                    .as_ref()
+                    .is_some_and(|path| known.contains_key(path)),
+            );
+            let new_chksum = chksums.insert((record.size, record.chksum.clone()));
+            let record = if per_file {
+                record
+            } else if new_chksum {
+                Record {
+                    mtime: None,
+                    path: None,
+                    ..record
+                }
+            } else {
+                continue;
+            };
+            match &mut writer {
+                Some(writer) => writer.write(&[record]).await?,
+                None => records.push(record),
+            }
+        }
+        if self.update.is_some() {
+            info!(
+                "{num_unchanged} of {num_analyzed} files unchanged, {} new or modified, {} deleted",
+                num_analyzed - num_unchanged - num_unreadable,
+                known.len() - num_known
+            );
+            if num_unreadable > 0 {
+                warn!("{num_unreadable} files could not be read, and kept their previous record");
+            }
         }
 
-        info!("Analyzed {num_analyzed} files, writing to output...");
-        write_output(&file_map, &self.output_file).await
+        match writer {
+            Some(writer) => {
+                info!("Analyzed {num_analyzed} files");
+                writer.finish(num_analyzed, chksums.len()).await
+            }
+            None => {
+                info!("Analyzed {num_analyzed} files, writing to output...");
+                let header = Header::new(layout, algo, root, num_analyzed, chksums.len());
+                write_inventory(&header, &mut records, format, &output_file).await
+            }
+        }
     }
 }
 
-async fn write_output<P: AsRef<Path>>(
-    file_map: &HashMap<usize, HashSet<String>>,
-    output_file: &P,
-) -> Result<()> {
-    let file = output_file.open_rw().await?;
-    let mut writer = BufWriter::new(file);
-
-    for (size, list) in file_map {
-        let mut buffer = format!("{size}:");
-        let mut stream = list.iter();
-        if let Some(item) = stream.next() {
-            write!(buffer, "{item}").unwrap();
+/// Where a record of [`Analyze::analyze`] comes from
+enum Origin {
+    /// The file was hashed
+    Hashed,
+    /// The file is recorded by the analysis being updated, with the same size and mtime
+    Unchanged,
+    /// The file could not be read, so its record in the analysis being updated is kept
+    Unreadable,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Analysis of `local_path` into `output_file`, with a record per file when `per_file`
+    fn analysis(local_path: &Path, output_file: &Path, per_file: bool) -> Analyze {
+        Analyze {
+            output_file: output_file.to_path_buf(),
+            local_path: local_path.to_path_buf(),
+            per_file,
+            format: Format::Jsonl,
+            stream: false,
+            update: None,
         }
-        for item in stream {
-            write!(buffer, ",{item}").unwrap();
+    }
+
+    fn update(output_file: &Path) -> Analyze {
+        Analyze {
+            update: Some(output_file.to_path_buf()),
+            ..analysis(Path::new("."), Path::new("dedup.out"), false)
         }
-        writeln!(buffer).unwrap();
+    }
 
-        writer.write_all(buffer.as_bytes()).await?;
+    /// Records of `output_file` by path
+    async fn recorded(output_file: &Path) -> HashMap<PathBuf, Record> {
+        let inventory = Inventory::read_all(output_file, None).await.unwrap();
+        assert_eq!(inventory.format, Format::Jsonl);
+        inventory
+            .into_records()
+            .into_iter()
+            .map(|record| (record.path.clone().unwrap(), record))
+            .collect()
     }
 
-    writer.flush().await?;
+    #[tokio::test]
+    async fn updates_keep_unchanged_records_and_refresh_the_rest() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = dir.path().join("tree");
+        std::fs::create_dir(&tree).unwrap();
+        for (name, contents) in [("same", "same"), ("modified", "old"), ("deleted", "gone")] {
+            std::fs::write(tree.join(name), contents).unwrap();
+        }
+        let output_file = dir.path().join("analysis.jsonl");
+        let walker = Walker::default();
+        analysis(&tree, &output_file, true)
+            .analyze(None, &walker)
+            .await
+            .unwrap();
+        let before = recorded(&output_file).await;
+
+        // A record only kept as it was tells unchanged files were not hashed again
+        let inventory = Inventory::read_all(&output_file, None).await.unwrap();
+        let header = inventory.header.clone().unwrap();
+        let mut records = inventory.into_records();
+        for record in &mut records {
+            if record.path.as_deref() == Some(Path::new("same")) {
+                record.chksum = "1".to_string();
+            }
+        }
+        write_inventory(&header, &mut records, Format::Jsonl, &output_file)
+            .await
+            .unwrap();
+
+        std::fs::write(tree.join("modified"), "modified").unwrap();
+        std::fs::remove_file(tree.join("deleted")).unwrap();
+        std::fs::write(tree.join("added"), "added").unwrap();
+        update(&output_file).analyze(None, &walker).await.unwrap();
 
-    debug!(
-        "Analysis written to file {}",
-        output_file.as_ref().display()
-    );
-    Ok(())
+        let after = recorded(&output_file).await;
+        let mut names: Vec<_> = after.keys().map(|path| path.to_str().unwrap()).collect();
+        names.sort_unstable();
+        assert_eq!(names, ["added", "modified", "same"]);
+        assert_eq!(after[Path::new("same")].chksum, "1");
+        let modified = &after[Path::new("modified")];
+        assert_eq!(modified.size, Some("modified".len()));
+        assert_ne!(modified.chksum, before[Path::new("modified")].chksum);
+    }
+
+    #[tokio::test]
+    async fn analyses_without_per_file_records_cannot_be_updated() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a"), "a").unwrap();
+        let output_file = dir.path().join("analysis.jsonl");
+        let walker = Walker::default();
+        analysis(dir.path(), &output_file, false)
+            .analyze(None, &walker)
+            .await
+            .unwrap();
+        let err = update(&output_file)
+            .analyze(None, &walker)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("holds no per-file records, so it cannot be updated"),
+            "{err}"
+        );
+    }
 }
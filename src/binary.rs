@@ -0,0 +1,380 @@
+use crate::hasher::HashAlgo;
+use crate::inventory::{Header, Layout};
+use anyhow::Result;
+use std::cmp::Ordering;
+
+/// Leads binary analysis files
+pub const MAGIC: &[u8; 8] = b"DEDUPBIN";
+
+/// Records per block. Lookups scan one block after searching the index
+const BLOCK_LEN: usize = 256;
+
+const FOOTER_LEN: usize = 16;
+
+/// Bytes of a binary analysis file, mapped or read whole
+pub enum Bytes {
+    Mapped(memmap2::Mmap),
+    Owned(Vec<u8>),
+}
+
+impl std::ops::Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Bytes::Mapped(mmap) => mmap,
+            Bytes::Owned(vec) => vec,
+        }
+    }
+}
+
+impl std::fmt::Debug for Bytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} bytes", self.len())
+    }
+}
+
+/// Encodes the distinct `(size, digest)` pairs of `records`, which need not be sorted
+pub fn encode(header: &Header, mut records: Vec<(u64, Vec<u8>)>) -> Result<Vec<u8>> {
+    records.sort_unstable();
+    records.dedup();
+    let digest_len = header.algo.digest_len();
+
+    let header = header.to_json().to_string();
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend((header.len() as u32).to_le_bytes());
+    bytes.extend(header.as_bytes());
+
+    let mut index = Vec::new();
+    let mut num_blocks = 0_u64;
+    for block in records.chunks(BLOCK_LEN) {
+        let (first_size, first_digest) = &block[0];
+        index.extend(first_size.to_le_bytes());
+        index.extend(first_digest);
+        index.extend((bytes.len() as u64).to_le_bytes());
+        index.extend((block.len() as u32).to_le_bytes());
+        num_blocks += 1;
+
+        let mut prev_size = 0;
+        for (size, digest) in block {
+            anyhow::ensure!(digest.len() == digest_len, "Digest of the wrong length");
+            write_varint(&mut bytes, size - prev_size);
+            bytes.extend(digest);
+            prev_size = *size;
+        }
+    }
+
+    let index_offset = bytes.len() as u64;
+    bytes.extend(index);
+    bytes.extend(index_offset.to_le_bytes());
+    bytes.extend(num_blocks.to_le_bytes());
+    Ok(bytes)
+}
+
+/// Binary analysis file, searched in place rather than loaded into memory. Integers are little
+/// endian. The file holds, in order:
+///
+/// - [`MAGIC`], the length of the header as a `u32` and the header as a JSON object
+/// - blocks of up to [`BLOCK_LEN`] records sorted by size and raw digest, each record being the
+///   difference of its size to that of the record before it as a LEB128 varint, and its digest
+/// - an index with an entry per block: the size and digest of its first record as a `u64` and raw
+///   bytes, the offset of the block as a `u64` and its number of records as a `u32`
+/// - the offset of the index and the number of blocks, as `u64`s
+#[derive(Debug)]
+pub struct Packed {
+    bytes: Bytes,
+    pub header: Header,
+    digest_len: usize,
+    index_offset: usize,
+    num_blocks: usize,
+    num_records: usize,
+}
+
+impl Packed {
+    /// Checks the header, index and footer of `bytes`. Blocks are only checked when searched
+    pub fn open(bytes: Bytes) -> Result<Self> {
+        let corrupt = || anyhow::anyhow!("Corrupt binary analysis file");
+        anyhow::ensure!(bytes.starts_with(MAGIC), "Not a binary analysis file");
+        let header_len = read_u32(&bytes, MAGIC.len()).ok_or_else(corrupt)? as usize;
+        let header_start = MAGIC.len() + 4;
+        let header_bytes = bytes
+            .get(header_start..header_start + header_len)
+            .ok_or_else(corrupt)?;
+        let header = match serde_json::from_slice(header_bytes)? {
+            serde_json::Value::Object(object) => Header::parse_json(object)?,
+            _ => return Err(corrupt()),
+        };
+        anyhow::ensure!(!header.streamed, corrupt());
+        anyhow::ensure!(
+            header.layout == Layout::Sizes,
+            "Binary analysis files cannot hold {} records",
+            header.layout
+        );
+        let digest_len = header.algo.digest_len();
+
+        let footer = bytes.len().checked_sub(FOOTER_LEN).ok_or_else(corrupt)?;
+        let index_offset = read_u64(&bytes, footer).ok_or_else(corrupt)? as usize;
+        let num_blocks = read_u64(&bytes, footer + 8).ok_or_else(corrupt)? as usize;
+        let index_len = num_blocks
+            .checked_mul(index_entry_len(digest_len))
+            .ok_or_else(corrupt)?;
+        anyhow::ensure!(
+            index_offset >= header_start + header_len
+                && index_offset.checked_add(index_len) == Some(footer),
+            corrupt()
+        );
+
+        let mut packed = Self {
+            bytes,
+            header,
+            digest_len,
+            index_offset,
+            num_blocks,
+            num_records: 0,
+        };
+        // Blocks lie between the header and the index, in order
+        let mut prev_offset = index_offset;
+        for block in (0..num_blocks).rev() {
+            let (_, _, offset, count) = packed.entry(block).ok_or_else(corrupt)?;
+            let len = prev_offset.checked_sub(offset).ok_or_else(corrupt)?;
+            anyhow::ensure!(
+                count > 0 && (count * (1 + digest_len)..=count * (10 + digest_len)).contains(&len),
+                corrupt()
+            );
+            prev_offset = offset;
+            packed.num_records += count;
+        }
+        anyhow::ensure!(prev_offset >= header_start + header_len, corrupt());
+        anyhow::ensure!(
+            packed.num_records == packed.header.checksums,
+            "Binary analysis file holds {} checksums while its header promises {}, it may be truncated",
+            packed.num_records,
+            packed.header.checksums
+        );
+        Ok(packed)
+    }
+
+    /// Number of distinct checksums
+    pub fn len(&self) -> usize {
+        self.num_records
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_records == 0
+    }
+
+    pub fn algo(&self) -> HashAlgo {
+        self.header.algo
+    }
+
+    /// Decodes every record, in order
+    pub fn records(&self) -> Result<Vec<(u64, &[u8])>> {
+        let corrupt = || anyhow::anyhow!("Corrupt binary analysis file");
+        let mut records = Vec::with_capacity(self.num_records);
+        for block in 0..self.num_blocks {
+            let (_, _, mut offset, count) = self.entry(block).ok_or_else(corrupt)?;
+            let mut size = 0;
+            for _ in 0..count {
+                let (delta, len) = read_varint(&self.bytes, offset).ok_or_else(corrupt)?;
+                size += delta;
+                offset += len;
+                let digest = self
+                    .bytes
+                    .get(offset..offset + self.digest_len)
+                    .ok_or_else(corrupt)?;
+                offset += self.digest_len;
+                records.push((size, digest));
+            }
+        }
+        Ok(records)
+    }
+
+    /// Size and digest of the first record, offset and record count of `block`
+    fn entry(&self, block: usize) -> Option<(u64, &[u8], usize, usize)> {
+        let start = self.index_offset + block * index_entry_len(self.digest_len);
+        let size = read_u64(&self.bytes, start)?;
+        let digest = self.bytes.get(start + 8..start + 8 + self.digest_len)?;
+        let offset = read_u64(&self.bytes, start + 8 + self.digest_len)? as usize;
+        let count = read_u32(&self.bytes, start + 16 + self.digest_len)? as usize;
+        Some((size, digest, offset, count))
+    }
+
+    /// Whether a record of `size` with the raw `digest` is present
+    pub fn contains(&self, size: u64, digest: &[u8]) -> bool {
+        // Index of the first block starting past the record, which can only be in the one before
+        let (mut low, mut high) = (0, self.num_blocks);
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.entry(mid) {
+                Some((first_size, first_digest, _, _))
+                    if (first_size, first_digest) <= (size, digest) =>
+                {
+                    low = mid + 1
+                }
+                _ => high = mid,
+            }
+        }
+        let Some(block) = low.checked_sub(1) else {
+            return false;
+        };
+        let Some((_, _, mut offset, count)) = self.entry(block) else {
+            return false;
+        };
+
+        let mut record_size = 0;
+        for _ in 0..count {
+            let Some((delta, len)) = read_varint(&self.bytes, offset) else {
+                return false;
+            };
+            record_size += delta;
+            offset += len;
+            let Some(record_digest) = self.bytes.get(offset..offset + self.digest_len) else {
+                return false;
+            };
+            offset += self.digest_len;
+            match (record_size, record_digest).cmp(&(size, digest)) {
+                Ordering::Less => continue,
+                Ordering::Equal => return true,
+                Ordering::Greater => return false,
+            }
+        }
+        false
+    }
+}
+
+fn index_entry_len(digest_len: usize) -> usize {
+    8 + digest_len + 8 + 4
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+/// Returns the varint at `at` and its length in bytes
+fn read_varint(bytes: &[u8], at: usize) -> Option<(u64, usize)> {
+    let mut value = 0_u64;
+    for (i, byte) in bytes.get(at..)?.iter().take(10).enumerate() {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn header(checksums: usize) -> Header {
+        Header::new(
+            Layout::Sizes,
+            HashAlgo::Xxh3_64,
+            PathBuf::from("/"),
+            checksums,
+            checksums,
+        )
+    }
+
+    /// Records spanning several blocks, with sizes far enough apart to need long varints
+    fn records() -> Vec<(u64, Vec<u8>)> {
+        (0..BLOCK_LEN as u64 * 2 + 10)
+            .map(|i| (i * i * 1_000_003, i.to_be_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn records_round_trip_in_order() {
+        let mut records = records();
+        let mut unsorted = records.clone();
+        unsorted.reverse();
+        unsorted.push(unsorted[0].clone());
+        let bytes = encode(&header(records.len()), unsorted).unwrap();
+        let packed = Packed::open(Bytes::Owned(bytes)).unwrap();
+        assert_eq!(packed.len(), records.len());
+
+        records.sort_unstable();
+        let decoded = packed.records().unwrap();
+        let decoded: Vec<_> = decoded.into_iter().map(|(s, d)| (s, d.to_vec())).collect();
+        assert_eq!(decoded, records);
+    }
+
+    #[test]
+    fn records_are_found_in_any_block() {
+        let records = records();
+        let bytes = encode(&header(records.len()), records.clone()).unwrap();
+        let packed = Packed::open(Bytes::Owned(bytes)).unwrap();
+        for (size, digest) in &records {
+            assert!(packed.contains(*size, digest), "{size}");
+            assert!(!packed.contains(size + 1, digest), "{size}");
+        }
+        assert!(!packed.contains(0, &[0xff; 8]));
+        assert!(!packed.contains(u64::MAX, &[0; 8]));
+    }
+
+    #[test]
+    fn empty_files_hold_no_records() {
+        let bytes = encode(&header(0), Vec::new()).unwrap();
+        let packed = Packed::open(Bytes::Owned(bytes)).unwrap();
+        assert!(packed.is_empty());
+        assert!(packed.records().unwrap().is_empty());
+        assert!(!packed.contains(0, &[0; 8]));
+    }
+
+    #[test]
+    fn digests_of_the_wrong_length_are_refused() {
+        assert!(encode(&header(1), vec![(1, vec![0; 16])]).is_err());
+    }
+
+    #[test]
+    fn truncated_or_miscounted_files_are_refused() {
+        let records = records();
+        let bytes = encode(&header(records.len()), records.clone()).unwrap();
+        let truncated = bytes[..bytes.len() - 1].to_vec();
+        assert!(Packed::open(Bytes::Owned(truncated)).is_err());
+
+        let bytes = encode(&header(records.len() + 1), records).unwrap();
+        let err = Packed::open(Bytes::Owned(bytes)).unwrap_err();
+        assert!(err.to_string().contains("it may be truncated"), "{err}");
+
+        let err = Packed::open(Bytes::Owned(b"DEDUPTXT".to_vec())).unwrap_err();
+        assert_eq!(err.to_string(), "Not a binary analysis file");
+    }
+
+    #[test]
+    fn varints_round_trip_at_their_boundaries() {
+        for (value, len) in [
+            (0, 1),
+            (127, 1),
+            (128, 2),
+            (16_383, 2),
+            (16_384, 3),
+            (u64::MAX, 10),
+        ] {
+            let mut bytes = vec![0xff];
+            write_varint(&mut bytes, value);
+            assert_eq!(bytes.len(), 1 + len, "{value}");
+            assert_eq!(read_varint(&bytes, 1), Some((value, len)), "{value}");
+        }
+    }
+
+    #[test]
+    fn unterminated_varints_are_refused() {
+        assert_eq!(read_varint(&[0x80, 0x80], 0), None);
+        assert_eq!(read_varint(&[0x80; 11], 0), None);
+        assert_eq!(read_varint(&[1], 1), None);
+    }
+}
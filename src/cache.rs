@@ -0,0 +1,398 @@
+use crate::hasher::HashAlgo;
+use anyhow::Result;
+use clap::{Args, Subcommand};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::Metadata;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+/// Leads the cache file, and is bumped whenever the layout of the entries following it
+/// changes, so old caches get rebuilt. Entries are keyed by the bytes of absolute paths, which
+/// need not be UTF-8
+const CACHE_VERSION: u32 = 2;
+
+static CACHE: OnceLock<HashCache> = OnceLock::new();
+
+/// What identifies the contents a checksum was computed from
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime_ns: i64,
+}
+
+impl From<&Metadata> for Stamp {
+    fn from(md: &Metadata) -> Self {
+        Self {
+            dev: md.dev(),
+            ino: md.ino(),
+            size: md.size(),
+            mtime_ns: md.mtime() * 1_000_000_000 + md.mtime_nsec(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Entry {
+    stamp: Stamp,
+    size: usize,
+    /// Checksums keyed by the name of the algorithm that computed them
+    chksums: BTreeMap<String, String>,
+}
+
+/// Checksums of files computed by earlier runs, valid as long as the files keep their device,
+/// inode, size and modification time
+#[derive(Debug)]
+pub struct HashCache {
+    path: PathBuf,
+    entries: Mutex<HashMap<Vec<u8>, Entry>>,
+    /// Entries added or refreshed by this run, merged into the cache on disk when saving
+    updates: Mutex<HashMap<Vec<u8>, Entry>>,
+}
+
+impl HashCache {
+    /// `$XDG_CACHE_HOME/dedup/hashes.bin`
+    pub fn default_path() -> Result<PathBuf> {
+        let cache_home = match std::env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+            Some(cache_home) => PathBuf::from(cache_home),
+            None => match std::env::var_os("HOME") {
+                Some(home) => PathBuf::from(home).join(".cache"),
+                None => anyhow::bail!("Neither XDG_CACHE_HOME nor HOME are set"),
+            },
+        };
+        Ok(cache_home.join("dedup").join("hashes.bin"))
+    }
+
+    /// Loads the cache at `path`. A missing cache is empty, and one that fails to decode is
+    /// rebuilt from scratch
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let entries = read_entries(&path)?;
+        debug!(
+            "Loaded {} cached checksums from {}",
+            entries.len(),
+            path.display()
+        );
+        Ok(Self {
+            path,
+            entries: Mutex::new(entries),
+            updates: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Installs `self` as the cache consulted by [`cached`] and [`remember`]
+    pub fn install(self) -> Result<()> {
+        CACHE
+            .set(self)
+            .map_err(|_| anyhow::anyhow!("Hash cache already installed"))
+    }
+
+    /// The cache installed for this run, if any
+    pub fn installed() -> Option<&'static HashCache> {
+        CACHE.get()
+    }
+
+    fn get(&self, path: &Path, md: &Metadata, algo: HashAlgo) -> Option<(usize, String)> {
+        let key = key_of(path).ok()?;
+        let entries = self.entries.lock().unwrap();
+        let entry = entries
+            .get(&key)
+            .filter(|entry| entry.stamp == Stamp::from(md))?;
+        let chksum = entry.chksums.get(&algo.to_string())?;
+        Some((entry.size, chksum.clone()))
+    }
+
+    fn insert(&self, path: &Path, md: &Metadata, algo: HashAlgo, size: usize, chksum: String) {
+        let Ok(key) = key_of(path) else { return };
+        let stamp = Stamp::from(md);
+        let mut entries = self.entries.lock().unwrap();
+        // Checksums by other algorithms remain valid as long as the file did not change
+        let entry = entries
+            .entry(key.clone())
+            .and_modify(|entry| {
+                if entry.stamp != stamp {
+                    entry.chksums.clear();
+                }
+                entry.stamp = stamp;
+                entry.size = size;
+            })
+            .or_insert_with(|| Entry {
+                stamp,
+                size,
+                chksums: BTreeMap::new(),
+            });
+        entry.chksums.insert(algo.to_string(), chksum);
+        self.updates.lock().unwrap().insert(key, entry.clone());
+    }
+
+    /// Merges the checksums computed by this run into the cache on disk
+    pub fn save(&self) -> Result<()> {
+        let updates = std::mem::take(&mut *self.updates.lock().unwrap());
+        if updates.is_empty() {
+            return Ok(());
+        }
+
+        debug!(
+            "Saving {} new checksums to {}",
+            updates.len(),
+            self.path.display()
+        );
+        let _lock = lock(&self.path)?;
+        // Other runs may have saved since this one loaded, so start over from what is on disk
+        let mut entries = read_entries(&self.path)?;
+        entries.extend(updates);
+        write_entries(&self.path, entries)
+    }
+}
+
+/// Returns the `algo` checksum cached for `path`, if still valid for `md`
+pub fn cached(path: &Path, md: &Metadata, algo: HashAlgo) -> Option<(usize, String)> {
+    HashCache::installed()?.get(path, md, algo)
+}
+
+/// Caches the `algo` checksum of `path`, valid as long as `md` describes it
+pub fn remember(path: &Path, md: &Metadata, algo: HashAlgo, size: usize, chksum: String) {
+    if let Some(cache) = HashCache::installed() {
+        cache.insert(path, md, algo, size, chksum);
+    }
+}
+
+fn key_of(path: &Path) -> Result<Vec<u8>> {
+    Ok(std::path::absolute(path)?.into_os_string().into_vec())
+}
+
+fn read_entries(path: &Path) -> Result<HashMap<Vec<u8>, Entry>> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    // The version is decoded on its own, as later layouts may not decode at all
+    let config = bincode::config::standard();
+    let decoded =
+        bincode::serde::decode_from_slice::<u32, _>(&bytes, config).and_then(|(version, read)| {
+            match version {
+                CACHE_VERSION => bincode::serde::decode_from_slice(&bytes[read..], config)
+                    .map(|(entries, _)| Some(entries)),
+                _ => Ok(None),
+            }
+        });
+    match decoded {
+        Ok(Some(entries)) => Ok(entries),
+        Ok(None) => {
+            info!(
+                "Hash cache {} was written by another version, rebuilding it",
+                path.display()
+            );
+            Ok(HashMap::new())
+        }
+        Err(e) => {
+            warn!(
+                "Hash cache {} is corrupt, rebuilding it: {e}",
+                path.display()
+            );
+            Ok(HashMap::new())
+        }
+    }
+}
+
+/// Replaces the cache at `path` atomically, so readers never see a partial file
+fn write_entries(path: &Path, entries: HashMap<Vec<u8>, Entry>) -> Result<()> {
+    let config = bincode::config::standard();
+    let mut bytes = bincode::serde::encode_to_vec(CACHE_VERSION, config)?;
+    bytes.extend(bincode::serde::encode_to_vec(&entries, config)?);
+    let mut temp = path.as_os_str().to_os_string();
+    temp.push(format!(".tmp-{}", std::process::id()));
+    std::fs::write(&temp, bytes)?;
+    if let Err(e) = std::fs::rename(&temp, path) {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Takes the lock serializing writers of the cache at `path`, held until the file is dropped
+fn lock(path: &Path) -> Result<std::fs::File> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut lock_path = path.as_os_str().to_os_string();
+    lock_path.push(".lock");
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_path)?;
+    file.lock()?;
+    Ok(file)
+}
+
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+/// Manages the cache of checksums computed by earlier runs
+pub struct Cache {
+    #[command(subcommand)]
+    pub command: CacheCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheCommand {
+    /// Shows the location, size and number of entries of the cache
+    Stats,
+    /// Drops entries of files that were deleted or changed since they were cached
+    Prune,
+    /// Deletes the cache
+    Clear,
+}
+
+impl Cache {
+    pub fn run<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        match self.command {
+            CacheCommand::Stats => {
+                let entries = read_entries(path)?;
+                let disk_size = std::fs::metadata(path).map(|md| md.len()).unwrap_or(0);
+                let hashed_size = entries.values().map(|entry| entry.size as u64).sum::<u64>();
+                println!("Cache: {}", path.display());
+                println!("Size on disk: {disk_size} bytes");
+                println!("Entries: {}", entries.len());
+                println!("Hashed contents: {hashed_size} bytes");
+            }
+            CacheCommand::Prune => {
+                let _lock = lock(path)?;
+                let mut entries = read_entries(path)?;
+                let before = entries.len();
+                entries.retain(|key, entry| {
+                    let file = Path::new(std::ffi::OsStr::from_bytes(key));
+                    std::fs::metadata(file).is_ok_and(|md| Stamp::from(&md) == entry.stamp)
+                });
+                println!("Pruned {} of {before} entries", before - entries.len());
+                write_entries(path, entries)?;
+            }
+            CacheCommand::Clear => {
+                let _lock = lock(path)?;
+                match std::fs::remove_file(path) {
+                    Ok(()) => println!("Cleared {}", path.display()),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        println!("No cache at {}", path.display())
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A cache file and a file to cache the checksums of, in a fresh directory
+    fn setup() -> (tempfile::TempDir, PathBuf, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let (cache, file) = (dir.path().join("cache/hashes.bin"), dir.path().join("file"));
+        std::fs::write(&file, "contents").unwrap();
+        (dir, cache, file)
+    }
+
+    fn md(path: &Path) -> Metadata {
+        std::fs::metadata(path).unwrap()
+    }
+
+    #[test]
+    fn checksums_round_trip_through_disk() {
+        let (_dir, path, file) = setup();
+        let cache = HashCache::load(path.clone()).unwrap();
+        assert_eq!(cache.get(&file, &md(&file), HashAlgo::Md5), None);
+        cache.insert(&file, &md(&file), HashAlgo::Md5, 8, "0123".to_string());
+        cache.insert(&file, &md(&file), HashAlgo::Blake3, 8, "4567".to_string());
+        cache.save().unwrap();
+
+        let cache = HashCache::load(path).unwrap();
+        let get = |algo| cache.get(&file, &md(&file), algo);
+        assert_eq!(get(HashAlgo::Md5), Some((8, "0123".to_string())));
+        assert_eq!(get(HashAlgo::Blake3), Some((8, "4567".to_string())));
+        assert_eq!(get(HashAlgo::Sha256), None);
+    }
+
+    #[test]
+    fn changed_files_miss_and_lose_their_other_checksums() {
+        let (_dir, path, file) = setup();
+        let cache = HashCache::load(path).unwrap();
+        cache.insert(&file, &md(&file), HashAlgo::Md5, 8, "0123".to_string());
+        std::fs::write(&file, "changed contents").unwrap();
+        assert_eq!(cache.get(&file, &md(&file), HashAlgo::Md5), None);
+
+        cache.insert(&file, &md(&file), HashAlgo::Blake3, 16, "4567".to_string());
+        assert_eq!(cache.get(&file, &md(&file), HashAlgo::Md5), None);
+        let hit = cache.get(&file, &md(&file), HashAlgo::Blake3);
+        assert_eq!(hit, Some((16, "4567".to_string())));
+    }
+
+    #[test]
+    fn saves_merge_with_those_of_other_runs() {
+        let (dir, path, file) = setup();
+        let other = dir.path().join("other");
+        std::fs::write(&other, "other").unwrap();
+        let (first, second) = (
+            HashCache::load(path.clone()).unwrap(),
+            HashCache::load(path.clone()).unwrap(),
+        );
+        first.insert(&file, &md(&file), HashAlgo::Md5, 8, "0123".to_string());
+        second.insert(&other, &md(&other), HashAlgo::Md5, 5, "4567".to_string());
+        first.save().unwrap();
+        second.save().unwrap();
+        assert_eq!(read_entries(&path).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn caches_of_other_versions_or_corrupt_ones_are_rebuilt() {
+        let (_dir, path, file) = setup();
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let config = bincode::config::standard();
+        let mut newer = bincode::serde::encode_to_vec(CACHE_VERSION + 1, config).unwrap();
+        newer.extend(b"layout unknown to this version");
+        for bytes in [newer, vec![0xff; 3]] {
+            std::fs::write(&path, bytes).unwrap();
+            let cache = HashCache::load(path.clone()).unwrap();
+            assert_eq!(cache.get(&file, &md(&file), HashAlgo::Md5), None);
+            cache.insert(&file, &md(&file), HashAlgo::Md5, 8, "0123".to_string());
+            cache.save().unwrap();
+            assert_eq!(read_entries(&path).unwrap().len(), 1);
+        }
+    }
+
+    #[test]
+    fn unreadable_caches_fail_to_load() {
+        let (dir, _path, _file) = setup();
+        assert!(HashCache::load(dir.path().to_path_buf()).is_err());
+    }
+
+    #[test]
+    fn pruning_drops_deleted_and_changed_files() {
+        let (dir, path, file) = setup();
+        let (changed, deleted) = (dir.path().join("changed"), dir.path().join("deleted"));
+        let cache = HashCache::load(path.clone()).unwrap();
+        for file in [&file, &changed, &deleted] {
+            std::fs::write(file, "contents").unwrap();
+            cache.insert(file, &md(file), HashAlgo::Md5, 8, "0123".to_string());
+        }
+        cache.save().unwrap();
+        std::fs::write(&changed, "changed contents").unwrap();
+        std::fs::remove_file(&deleted).unwrap();
+
+        let prune = Cache {
+            command: CacheCommand::Prune,
+        };
+        prune.run(&path).unwrap();
+        let entries = read_entries(&path).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries.contains_key(&key_of(&file).unwrap()));
+    }
+}
@@ -1,18 +1,30 @@
 use anyhow::Result;
-use log::{error, trace};
-use std::path::{Path, PathBuf};
-use tokio::fs::{File, OpenOptions};
+use clap::{Args, ValueEnum};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
+use log::{debug, error, trace, warn};
+use std::collections::HashSet;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::fs::{File, OpenOptions, canonicalize, metadata};
 use tokio::io::{AsyncBufReadExt, BufReader};
-use walkdir::WalkDir;
 
 const CHUNK_SIZE: usize = 1024 * 1024;
 
 #[allow(async_fn_in_trait)]
 pub trait FileOps: AsRef<Path> {
     async fn remove_file(&self, commit: bool) -> Result<()>;
+    async fn hardlink_to(&self, survivor: &Path, commit: bool) -> Result<()>;
+    async fn reflink_to(&self, survivor: &Path, commit: bool) -> Result<()>;
+    async fn symlink_to(&self, survivor: &Path, relative: bool, commit: bool) -> Result<()>;
+    async fn move_to(&self, dest: &Path) -> Result<()>;
+    async fn replace_with_copy(&self, source: &Path, commit: bool) -> Result<()>;
     async fn open_ro(&self) -> Result<File>;
     async fn open_rw(&self) -> Result<File>;
     async fn dup_of(&self, other: &Self) -> Result<bool>;
+    async fn same_file(&self, other: &Path) -> Result<bool>;
 }
 
 impl<P> FileOps for P
@@ -39,6 +51,149 @@ where
         Ok(())
     }
 
+    async fn hardlink_to(&self, survivor: &Path, commit: bool) -> Result<()> {
+        let this = self.as_ref();
+        let (this_md, that_md) = (metadata(this).await?, metadata(survivor).await?);
+        if this_md.dev() != that_md.dev() {
+            anyhow::bail!(
+                "Cannot hard link {} to {} across filesystems",
+                this.display(),
+                survivor.display()
+            );
+        }
+        if this_md.ino() == that_md.ino() {
+            trace!(
+                "{}: already linked to {}",
+                this.display(),
+                survivor.display()
+            );
+            return Ok(());
+        }
+
+        if !commit {
+            trace!(
+                "{}: candidate for hard linking to {}",
+                this.display(),
+                survivor.display()
+            );
+            return Ok(());
+        }
+
+        trace!("{}: hard linking to {}", this.display(), survivor.display());
+        let temp = temp_sibling(this)?;
+        // Hard links to a symlink would link the symlink itself rather than what it points to
+        tokio::fs::hard_link(canonicalize(survivor).await?, &temp).await?;
+        replace_with(&temp, this).await
+    }
+
+    async fn reflink_to(&self, survivor: &Path, commit: bool) -> Result<()> {
+        let this = self.as_ref();
+        let (this_md, that_md) = (metadata(this).await?, metadata(survivor).await?);
+        if this_md.dev() != that_md.dev() {
+            anyhow::bail!(
+                "Cannot reflink {} to {} across filesystems",
+                this.display(),
+                survivor.display()
+            );
+        }
+        if this_md.ino() == that_md.ino() {
+            trace!(
+                "{}: already linked to {}",
+                this.display(),
+                survivor.display()
+            );
+            return Ok(());
+        }
+
+        if !commit {
+            trace!(
+                "{}: candidate for reflinking to {}",
+                this.display(),
+                survivor.display()
+            );
+            return Ok(());
+        }
+
+        trace!("{}: reflinking to {}", this.display(), survivor.display());
+        let (this, survivor) = (this.to_path_buf(), survivor.to_path_buf());
+        tokio::task::spawn_blocking(move || dedupe_file(&survivor, &this)).await?
+    }
+
+    async fn symlink_to(&self, survivor: &Path, relative: bool, commit: bool) -> Result<()> {
+        let this = self.as_ref();
+        let target = canonicalize(survivor).await?;
+        let target = if relative {
+            let this_dir = canonicalize(this).await?;
+            let this_dir = this_dir.parent().unwrap_or(Path::new("/"));
+            relative_to(&target, this_dir)
+        } else {
+            target
+        };
+
+        if !commit {
+            trace!(
+                "{}: candidate for symlinking to {}",
+                this.display(),
+                target.display()
+            );
+            return Ok(());
+        }
+
+        trace!("{}: symlinking to {}", this.display(), target.display());
+        let temp = temp_sibling(this)?;
+        tokio::fs::symlink(&target, &temp).await?;
+        replace_with(&temp, this).await
+    }
+
+    async fn move_to(&self, dest: &Path) -> Result<()> {
+        let this = self.as_ref();
+        if tokio::fs::symlink_metadata(dest).await.is_ok() {
+            anyhow::bail!(
+                "Cannot move {} over existing {}",
+                this.display(),
+                dest.display()
+            );
+        }
+        if let Some(parent) = dest.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        trace!("{}: moving to {}", this.display(), dest.display());
+        match tokio::fs::rename(this, dest).await {
+            Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+                // Copy to a hidden name first, so `dest` never holds a partial file
+                let temp = temp_sibling(dest)?;
+                if let Err(e) = tokio::fs::copy(this, &temp).await {
+                    let _ = tokio::fs::remove_file(&temp).await;
+                    return Err(e.into());
+                }
+                replace_with(&temp, dest).await?;
+                Ok(tokio::fs::remove_file(this).await?)
+            }
+            result => Ok(result?),
+        }
+    }
+
+    async fn replace_with_copy(&self, source: &Path, commit: bool) -> Result<()> {
+        let this = self.as_ref();
+        if !commit {
+            trace!(
+                "{}: candidate for copying {} over",
+                this.display(),
+                source.display()
+            );
+            return Ok(());
+        }
+
+        trace!("{}: copying {} over", this.display(), source.display());
+        let temp = temp_sibling(this)?;
+        if let Err(e) = tokio::fs::copy(source, &temp).await {
+            let _ = tokio::fs::remove_file(&temp).await;
+            return Err(e.into());
+        }
+        replace_with(&temp, this).await
+    }
+
     async fn open_rw(&self) -> Result<File> {
         trace!("{}: opening file in RW mode", self.as_ref().display());
         Ok(OpenOptions::new()
@@ -50,6 +205,12 @@ where
             .await?)
     }
 
+    /// Whether both paths lead to the same inode, through hard links or symlinks
+    async fn same_file(&self, other: &Path) -> Result<bool> {
+        let (this_md, that_md) = (metadata(self).await?, metadata(other).await?);
+        Ok((this_md.dev(), this_md.ino()) == (that_md.dev(), that_md.ino()))
+    }
+
     async fn dup_of(&self, other: &Self) -> Result<bool> {
         let (this, that) = (self.open_ro().await?, other.open_ro().await?);
         let mut this = BufReader::with_capacity(CHUNK_SIZE, this);
@@ -59,37 +220,1109 @@ where
             let this_slice = this.fill_buf().await?;
             let that_slice = that.fill_buf().await?;
 
-            if this_slice != that_slice {
+            if this_slice.is_empty() || that_slice.is_empty() {
+                return Ok(this_slice.is_empty() && that_slice.is_empty());
+            }
+
+            // Reads may come back short, so only compare what both sides have buffered
+            let len = this_slice.len().min(that_slice.len());
+            if this_slice[..len] != that_slice[..len] {
                 return Ok(false);
             }
 
-            if this_slice.is_empty() && that_slice.is_empty() {
-                return Ok(true);
+            this.consume(len);
+            that.consume(len);
+        }
+    }
+}
+
+/// `struct file_dedupe_range` from linux/fs.h, with room for a single destination
+#[repr(C)]
+struct FileDedupeRange {
+    src_offset: u64,
+    src_length: u64,
+    dest_count: u16,
+    reserved1: u16,
+    reserved2: u32,
+    info: FileDedupeRangeInfo,
+}
+
+/// `struct file_dedupe_range_info` from linux/fs.h
+#[repr(C)]
+struct FileDedupeRangeInfo {
+    dest_fd: i64,
+    dest_offset: u64,
+    bytes_deduped: u64,
+    status: i32,
+    reserved: u32,
+}
+
+/// `_IOWR(0x94, 54, struct file_dedupe_range)`, missing from libc
+const FIDEDUPERANGE: u32 = 0xC018_9436;
+const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+/// Filesystems cap the length deduped per call, btrfs at 16 MB
+const DEDUPE_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Makes `dest` share the extents of `src` using FIDEDUPERANGE rather than FICLONE: the kernel
+/// compares both ranges under lock and leaves `dest` alone if they differ, so a file modified
+/// since it was hashed is never clobbered, and `dest` keeps its inode, owner and permissions
+fn dedupe_file(src: &Path, dest: &Path) -> Result<()> {
+    let src_file = std::fs::File::open(src)?;
+    let dest_file = std::fs::OpenOptions::new().write(true).open(dest)?;
+    let len = src_file.metadata()?.len();
+    anyhow::ensure!(
+        len == dest_file.metadata()?.len(),
+        "{} and {} differ in size",
+        src.display(),
+        dest.display()
+    );
+
+    let mut offset = 0;
+    while offset < len {
+        let mut range = dedupe_range(offset, len, dest_file.as_raw_fd());
+        // SAFETY: `range` is a live file_dedupe_range with exactly `dest_count` infos
+        let ret = unsafe { libc::ioctl(src_file.as_raw_fd(), FIDEDUPERANGE as _, &mut range) };
+        let errno = std::io::Error::last_os_error().raw_os_error().unwrap_or(0);
+        offset += dedupe_reply(ret, errno, &range.info, src, dest, offset)?;
+    }
+    Ok(())
+}
+
+/// Request deduping the chunk of a `len` byte file at `offset` into the same range of the file
+/// open at `dest_fd`
+fn dedupe_range(offset: u64, len: u64, dest_fd: i32) -> FileDedupeRange {
+    FileDedupeRange {
+        src_offset: offset,
+        src_length: DEDUPE_CHUNK_SIZE.min(len - offset),
+        dest_count: 1,
+        reserved1: 0,
+        reserved2: 0,
+        info: FileDedupeRangeInfo {
+            dest_fd: dest_fd.into(),
+            dest_offset: offset,
+            bytes_deduped: 0,
+            status: 0,
+            reserved: 0,
+        },
+    }
+}
+
+/// Reads the reply to a FIDEDUPERANGE call at `offset` that returned `ret`, with `errno` set
+/// on failure, and returns the number of bytes deduped
+fn dedupe_reply(
+    ret: i32,
+    errno: i32,
+    info: &FileDedupeRangeInfo,
+    src: &Path,
+    dest: &Path,
+    offset: u64,
+) -> Result<u64> {
+    let errno = match (ret, info.status) {
+        (0, 0) => None,
+        (0, FILE_DEDUPE_RANGE_DIFFERS) => anyhow::bail!(
+            "{} and {} differ at offset {offset}",
+            src.display(),
+            dest.display()
+        ),
+        (0, status) => Some(-status),
+        _ => Some(errno),
+    };
+    match errno {
+        None => {}
+        Some(libc::EOPNOTSUPP | libc::ENOTTY) => {
+            anyhow::bail!("{}: filesystem does not support reflinks", dest.display())
+        }
+        Some(libc::EXDEV) => anyhow::bail!(
+            "Cannot reflink {} to {} across filesystems",
+            dest.display(),
+            src.display()
+        ),
+        Some(errno) => return Err(std::io::Error::from_raw_os_error(errno).into()),
+    }
+    anyhow::ensure!(
+        info.bytes_deduped > 0,
+        "{}: no progress deduping at offset {offset}",
+        dest.display()
+    );
+    Ok(info.bytes_deduped)
+}
+
+/// Returns `path` relative to the directory `base`, both being canonical
+fn relative_to(path: &Path, base: &Path) -> PathBuf {
+    let (mut path_iter, mut base_iter) =
+        (path.components().peekable(), base.components().peekable());
+    while let (Some(a), Some(b)) = (path_iter.peek(), base_iter.peek())
+        && a == b
+    {
+        path_iter.next();
+        base_iter.next();
+    }
+    base_iter
+        .map(|_| Component::ParentDir)
+        .chain(path_iter)
+        .collect()
+}
+
+/// Returns a hidden path next to `path`, for staging its replacement
+pub fn temp_sibling(path: &Path) -> Result<PathBuf> {
+    let Some(name) = path.file_name() else {
+        anyhow::bail!("{}: not a file", path.display());
+    };
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(format!(".dedup-{}", std::process::id()));
+    Ok(path.with_file_name(temp_name))
+}
+
+/// Atomically renames `temp` over `path`, cleaning `temp` up if that fails
+pub async fn replace_with(temp: &Path, path: &Path) -> Result<()> {
+    if let Err(e) = tokio::fs::rename(temp, path).await {
+        let _ = tokio::fs::remove_file(temp).await;
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Options narrowing down the files walked by every mode
+#[derive(Args, Debug, Default, Clone)]
+pub struct WalkOpts {
+    /// Walks only the files matching this glob, relative to the root being walked. Globs without
+    /// a `/` match names at any depth, those ending in `/` only match directories, and `**`
+    /// matches any number of directories. Repeatable
+    #[arg(long)]
+    pub include: Vec<String>,
+
+    /// Skips the files and directories matching this glob, matched like --include. Wins over
+    /// --include. Repeatable
+    #[arg(long)]
+    pub exclude: Vec<String>,
+
+    /// Skips what `.gitignore`, `.ignore` and `.dedupignore` files ignore, along with git's
+    /// global and per-repository excludes. Nested files take precedence over those above them,
+    /// and `.dedupignore` over `.ignore` over `.gitignore`
+    #[arg(long)]
+    pub respect_ignores: bool,
+
+    /// Skips files smaller than this many bytes, before hashing them. Takes k, M, G and T
+    /// suffixes, as powers of 1024
+    #[arg(long, value_parser = parse_size)]
+    pub min_size: Option<u64>,
+
+    /// Skips files larger than this many bytes, before hashing them. Takes suffixes like
+    /// --min-size
+    #[arg(long, value_parser = parse_size)]
+    pub max_size: Option<u64>,
+
+    /// What to do with empty files, which are all alike but often serve as markers
+    #[arg(long, value_enum, default_value_t)]
+    pub empty_files: EmptyFiles,
+
+    /// Stays on the filesystem of each root being walked, like `find -xdev`, except for the
+    /// mount points given to --mount-allow
+    #[arg(short = 'x', long)]
+    pub one_file_system: bool,
+
+    /// Mount point to descend into despite --one-file-system, along with the rest of its
+    /// filesystem. Repeatable
+    #[arg(long, value_name = "PATH")]
+    pub mount_allow: Vec<PathBuf>,
+
+    /// Mount point, or any directory, never to descend into. Repeatable
+    #[arg(long, value_name = "PATH")]
+    pub mount_block: Vec<PathBuf>,
+
+    /// Descends into symlinked directories and reads symlinked files. Each is walked once
+    /// however many links lead to it, preferring its own path, and loops are skipped. Files
+    /// reached through a link are only compared against, never acted on
+    #[arg(long)]
+    pub follow_symlinks: bool,
+}
+
+/// Handling of empty files
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EmptyFiles {
+    /// Leave them out
+    #[default]
+    Skip,
+    /// Treat them as duplicates of each other, like any other file
+    Dedup,
+    /// Leave them out, naming each in the log
+    Report,
+}
+
+/// Parses a number of bytes like `4096`, `4k` or `1.5G`
+fn parse_size(size: &str) -> Result<u64, String> {
+    let lower = size.trim().to_ascii_lowercase();
+    let digits = lower.trim_end_matches(['b', 'i']);
+    let (number, shift) = match digits.char_indices().last() {
+        Some((at, unit @ ('k' | 'm' | 'g' | 't'))) => {
+            let shift = match unit {
+                'k' => 10,
+                'm' => 20,
+                'g' => 30,
+                _ => 40,
+            };
+            (&digits[..at], shift)
+        }
+        _ => (digits, 0),
+    };
+    let number = number
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|number| number.is_finite() && *number >= 0.0)
+        .ok_or_else(|| {
+            format!("invalid size {size}, expected a number of bytes like 512, 4k or 2G")
+        })?;
+    Ok((number * (1_u64 << shift) as f64) as u64)
+}
+
+impl WalkOpts {
+    pub fn walker(&self) -> Result<Walker> {
+        let include = match self.include.is_empty() {
+            true => None,
+            false => Some(Globs::new(&self.include)?),
+        };
+        if let (Some(min), Some(max)) = (self.min_size, self.max_size) {
+            anyhow::ensure!(min <= max, "--min-size {min} exceeds --max-size {max}");
+        }
+        let mut allowed_devices = HashSet::new();
+        for mount in &self.mount_allow {
+            allowed_devices.insert(mount_point(mount)?.dev());
+        }
+        let mut blocked_dirs = HashSet::new();
+        for mount in &self.mount_block {
+            let md = mount_point(mount)?;
+            blocked_dirs.insert((md.dev(), md.ino()));
+        }
+        Ok(Walker {
+            include,
+            exclude: Globs::new(&self.exclude)?,
+            respect_ignores: self.respect_ignores,
+            min_size: self.min_size,
+            max_size: self.max_size,
+            empty_files: self.empty_files,
+            one_file_system: self.one_file_system,
+            allowed_devices,
+            blocked_dirs,
+            skipped_dirs: HashSet::new(),
+            follow_symlinks: self.follow_symlinks,
+            walked_files: Arc::default(),
+        })
+    }
+}
+
+/// Looks up the directory at `path`, warning when it is no mount point. Bind mounts of a
+/// filesystem into itself cannot be told apart from plain directories
+fn mount_point(path: &Path) -> Result<std::fs::Metadata> {
+    let md = std::fs::metadata(path)
+        .map_err(|e| anyhow::anyhow!("Mount point {}: {e}", path.display()))?;
+    anyhow::ensure!(
+        md.is_dir(),
+        "Mount point {} is not a directory",
+        path.display()
+    );
+    let parent = std::fs::metadata(path.join(".."))?;
+    if parent.dev() == md.dev() && parent.ino() != md.ino() {
+        warn!("{} does not look like a mount point", path.display());
+    }
+    Ok(md)
+}
+
+/// Globs given to --include or --exclude. Patterns ending in `/` only match directories and
+/// whatever lies within them, like in `.gitignore` files
+#[derive(Debug, Default, Clone)]
+struct Globs {
+    any: GlobSet,
+    dirs: GlobSet,
+}
+
+impl Globs {
+    fn new(patterns: &[String]) -> Result<Self> {
+        let (mut any, mut dirs) = (GlobSetBuilder::new(), GlobSetBuilder::new());
+        for pattern in patterns {
+            let anchored = pattern.trim_start_matches("./");
+            let (anchored, dir_only) = match anchored.strip_suffix('/') {
+                Some(dir) => (dir.trim_end_matches('/'), true),
+                None => (anchored, false),
+            };
+            let anchored = match anchored.contains('/') {
+                true => anchored.trim_start_matches('/').to_string(),
+                false => format!("**/{anchored}"),
+            };
+            let glob = |anchored: &str| {
+                GlobBuilder::new(anchored)
+                    .literal_separator(true)
+                    .build()
+                    .map_err(|e| anyhow::anyhow!("Invalid glob {pattern}: {}", e.kind()))
+            };
+            match dir_only {
+                true => {
+                    dirs.add(glob(&anchored)?);
+                    any.add(glob(&format!("{anchored}/**"))?);
+                }
+                false => {
+                    any.add(glob(&anchored)?);
+                }
             }
+        }
+        Ok(Self {
+            any: any.build()?,
+            dirs: dirs.build()?,
+        })
+    }
+
+    fn is_match(&self, relative: &Path, is_dir: bool) -> bool {
+        self.any.is_match(relative) || (is_dir && self.dirs.is_match(relative))
+    }
+}
 
-            let (this_len, that_len) = (this_slice.len(), that_slice.len());
+/// Decides which files and directories are walked, as configured by [`WalkOpts`]
+#[derive(Debug, Default, Clone)]
+pub struct Walker {
+    include: Option<Globs>,
+    exclude: Globs,
+    respect_ignores: bool,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    empty_files: EmptyFiles,
+    one_file_system: bool,
+    /// Devices of the mount points given to --mount-allow
+    allowed_devices: HashSet<u64>,
+    /// Device and inode of the directories given to --mount-block
+    blocked_dirs: HashSet<(u64, u64)>,
+    /// Device and inode of directories dedup keeps files in, such as the quarantine directory
+    skipped_dirs: HashSet<(u64, u64)>,
+    follow_symlinks: bool,
+    /// Device and inode of the files walked so far when following symlinks, shared by every
+    /// walk of the run
+    walked_files: Arc<Mutex<HashSet<(u64, u64)>>>,
+}
+
+impl Walker {
+    /// Never descends into the directory at `path`, whichever tree it lies in
+    pub fn skip_dir(&mut self, path: &Path) -> Result<()> {
+        let md = std::fs::metadata(path)?;
+        self.skipped_dirs.insert((md.dev(), md.ino()));
+        Ok(())
+    }
+
+    /// Whether to walk the file or directory at `relative`, a path relative to the root being
+    /// walked. Directories are only skipped when excluded, as included files may lie within
+    fn admits(&self, relative: &Path, is_dir: bool) -> bool {
+        if self.exclude.is_match(relative, is_dir) {
+            trace!("{}: excluded", relative.display());
+            return false;
+        }
+        is_dir
+            || self
+                .include
+                .as_ref()
+                .is_none_or(|include| include.is_match(relative, is_dir))
+    }
 
-            this.consume(this_len);
-            that.consume(that_len);
+    /// Whether to walk the file at `path` of `size` bytes
+    fn admits_size(&self, path: &Path, size: u64) -> bool {
+        if size == 0 {
+            match self.empty_files {
+                EmptyFiles::Skip => return false,
+                EmptyFiles::Report => {
+                    warn!("{}: empty file, left alone", path.display());
+                    return false;
+                }
+                EmptyFiles::Dedup => {}
+            }
+        }
+        let admitted = self.min_size.is_none_or(|min| size >= min)
+            && self.max_size.is_none_or(|max| size <= max);
+        if !admitted {
+            trace!("{}: {size} bytes, out of the size range", path.display());
+        }
+        admitted
+    }
+
+    /// Whether to descend into the directory at `path`, described by `md`, under a root on
+    /// `root_device`
+    fn admits_dir(&self, path: &Path, md: &std::fs::Metadata, root_device: Option<u64>) -> bool {
+        if self.blocked_dirs.contains(&(md.dev(), md.ino())) {
+            debug!("{}: blocked mount point, not descending", path.display());
+            return false;
+        }
+        if self.skipped_dirs.contains(&(md.dev(), md.ino())) {
+            debug!("{}: kept by dedup, not descending", path.display());
+            return false;
+        }
+        if self.one_file_system
+            && root_device.is_some_and(|root_device| root_device != md.dev())
+            && !self.allowed_devices.contains(&md.dev())
+        {
+            debug!("{}: on another filesystem, not descending", path.display());
+            return false;
         }
+        true
+    }
+
+    /// Whether directories must be looked up while walking
+    fn checks_dirs(&self) -> bool {
+        self.one_file_system
+            || !self.blocked_dirs.is_empty()
+            || !self.skipped_dirs.is_empty()
+            || self.follow_symlinks
+    }
+
+    /// Whether sizes decide what is walked, so files must be looked up while walking
+    fn checks_size(&self) -> bool {
+        self.min_size.is_some() || self.max_size.is_some() || self.empty_files != EmptyFiles::Dedup
     }
 }
 
 pub trait DirOps {
-    fn walkdir(&self) -> impl Iterator<Item = PathBuf>;
+    fn walkdir(&self, walker: &Walker) -> impl Iterator<Item = PathBuf>;
 }
 
 impl<P> DirOps for P
 where
     P: AsRef<Path> + ?Sized,
 {
-    fn walkdir(&self) -> impl Iterator<Item = PathBuf> {
-        WalkDir::new(self).into_iter().filter_map(|entry| {
-            let path = entry
-                .inspect_err(|e| error!("{}: error while walking: {e}", self.as_ref().display()))
+    fn walkdir(&self, walker: &Walker) -> impl Iterator<Item = PathBuf> {
+        let root = self.as_ref();
+        let mut builder = WalkBuilder::new(root);
+        // Hidden files are walked like any other, and ignore files only read when asked to
+        builder
+            .standard_filters(false)
+            .parents(walker.respect_ignores)
+            .ignore(walker.respect_ignores)
+            .git_ignore(walker.respect_ignores)
+            .git_global(walker.respect_ignores)
+            .git_exclude(walker.respect_ignores)
+            .require_git(false);
+        if walker.respect_ignores {
+            builder.add_custom_ignore_filename(".dedupignore");
+        }
+        let (filter, filter_root) = (walker.clone(), root.to_path_buf());
+        let root_device = std::fs::metadata(root).map(|md| md.dev()).ok();
+        builder.follow_links(walker.follow_symlinks);
+        let links = Arc::new(Links::default());
+        let filter_links = links.clone();
+        builder.filter_entry(move |entry| {
+            if entry.depth() == 0 {
+                // Links above the root were followed by whoever named it
+                if filter.follow_symlinks
+                    && let Ok(md) = entry.metadata()
+                {
+                    filter_links
+                        .walked
+                        .lock()
+                        .unwrap()
+                        .insert((md.dev(), md.ino()));
+                }
+                return true;
+            }
+            let is_dir = entry
+                .file_type()
+                .is_some_and(|file_type| file_type.is_dir());
+            let admitted = entry
+                .path()
+                .strip_prefix(&filter_root)
+                .is_ok_and(|relative| filter.admits(relative, is_dir));
+            if !admitted {
+                return false;
+            }
+            if is_dir {
+                return !filter.checks_dirs()
+                    || entry.metadata().map_or(true, |md| {
+                        filter.admits_dir(entry.path(), &md, root_device)
+                            && (!filter.follow_symlinks || filter_links.admits_dir(entry, &md))
+                    });
+            }
+            if !filter.checks_size() {
+                return true;
+            }
+            // Files that cannot be looked up are left for the modes to report
+            entry
+                .metadata()
+                .map_or(true, |md| filter.admits_size(entry.path(), md.len()))
+        });
+
+        // Files reached through symlinks are held back until the end of the walk, and dropped
+        // when also reached under a path of their own, which actions can then be taken on
+        let (follow, walked_files) = (walker.follow_symlinks, walker.walked_files.clone());
+        let deferred = Arc::new(Mutex::new(Vec::new()));
+        let (defer, flush) = (deferred.clone(), deferred);
+        let walked = builder.build().filter_map(move |entry| {
+            let entry = entry
+                .inspect_err(|e| match is_loop(e) {
+                    true => debug!("{}: skipping loop: {e}", root.display()),
+                    false => error!("{}: error while walking: {e}", root.display()),
+                })
+                .ok()?;
+            // Followed links stand for their targets, dangling ones were reported above
+            if entry.file_type().is_none_or(|file_type| file_type.is_dir())
+                || (!follow && entry.path_is_symlink())
+            {
+                return None;
+            }
+            if !follow {
+                return Some(entry.into_path());
+            }
+            let Ok(md) = entry.metadata() else {
+                return Some(entry.into_path());
+            };
+            let id = (md.dev(), md.ino());
+            if links.reached(&entry) {
+                defer.lock().unwrap().push((entry.into_path(), id));
+                return None;
+            }
+            walked_files.lock().unwrap().insert(id);
+            Some(entry.into_path())
+        });
+        let walked_files = walker.walked_files.clone();
+        let linked = std::iter::once(()).flat_map(move |_| {
+            let deferred = std::mem::take(&mut *flush.lock().unwrap());
+            let walked_files = walked_files.clone();
+            deferred.into_iter().filter_map(move |(path, id)| {
+                if !walked_files.lock().unwrap().insert(id) {
+                    debug!("{}: already walked, skipping", path.display());
+                    return None;
+                }
+                Some(path)
+            })
+        });
+        walked.chain(linked)
+    }
+}
+
+/// What a walk following symlinks reached through them
+#[derive(Default)]
+struct Links {
+    /// Directories reached through a symlink, as the directory itself or one above it
+    dirs: Mutex<HashSet<PathBuf>>,
+    /// Device and inode of the directories walked
+    walked: Mutex<HashSet<(u64, u64)>>,
+}
+
+impl Links {
+    /// Whether `entry` was reached through a symlink, as itself or one of the directories above
+    fn reached(&self, entry: &ignore::DirEntry) -> bool {
+        entry.path_is_symlink()
+            || entry
+                .path()
+                .parent()
+                .is_some_and(|parent| self.dirs.lock().unwrap().contains(parent))
+    }
+
+    /// Whether to descend into the directory of `entry`, described by `md`. Directories reached
+    /// through a symlink are skipped once walked, which catches loops as well as directories
+    /// linked to more than once
+    fn admits_dir(&self, entry: &ignore::DirEntry, md: &std::fs::Metadata) -> bool {
+        let first = self.walked.lock().unwrap().insert((md.dev(), md.ino()));
+        if !self.reached(entry) {
+            return true;
+        }
+        self.dirs.lock().unwrap().insert(entry.path().to_path_buf());
+        if !first {
+            debug!("{}: already walked, skipping", entry.path().display());
+        }
+        first
+    }
+}
+
+/// Whether `e` reports a symlink leading back to a directory being walked
+fn is_loop(e: &ignore::Error) -> bool {
+    match e {
+        ignore::Error::Loop { .. } => true,
+        ignore::Error::WithPath { err, .. } | ignore::Error::WithDepth { err, .. } => is_loop(err),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dedupe_requests_match_the_kernel_abi() {
+        // _IOWR(0x94, 54, struct file_dedupe_range), whose infos trail it
+        let info_len = std::mem::size_of::<FileDedupeRangeInfo>();
+        let header_len = std::mem::size_of::<FileDedupeRange>() - info_len;
+        assert_eq!((header_len, info_len), (24, 32));
+        assert_eq!(
+            FIDEDUPERANGE,
+            (3 << 30) | ((header_len as u32) << 16) | (0x94 << 8) | 54
+        );
+    }
+
+    #[test]
+    fn dedupe_requests_cover_files_chunk_by_chunk() {
+        let len = DEDUPE_CHUNK_SIZE + 10;
+        let first = dedupe_range(0, len, 7);
+        assert_eq!(
+            (first.src_offset, first.src_length, first.dest_count),
+            (0, DEDUPE_CHUNK_SIZE, 1)
+        );
+        assert_eq!((first.info.dest_fd, first.info.dest_offset), (7, 0));
+        let last = dedupe_range(DEDUPE_CHUNK_SIZE, len, 7);
+        assert_eq!(
+            (last.src_offset, last.src_length, last.info.dest_offset),
+            (DEDUPE_CHUNK_SIZE, 10, DEDUPE_CHUNK_SIZE)
+        );
+    }
+
+    fn dedupe_reply_to(ret: i32, errno: i32, status: i32, bytes_deduped: u64) -> Result<u64> {
+        let info = FileDedupeRangeInfo {
+            dest_fd: 3,
+            dest_offset: 4096,
+            bytes_deduped,
+            status,
+            reserved: 0,
+        };
+        dedupe_reply(ret, errno, &info, Path::new("src"), Path::new("dest"), 4096)
+    }
+
+    #[test]
+    fn dedupe_replies_are_read_from_the_status_or_errno() {
+        assert_eq!(dedupe_reply_to(0, 0, 0, 4096).unwrap(), 4096);
+        assert_eq!(
+            dedupe_reply_to(0, 0, FILE_DEDUPE_RANGE_DIFFERS, 0)
+                .unwrap_err()
+                .to_string(),
+            "src and dest differ at offset 4096"
+        );
+        // Failures of the destination come as its status, those of the call in errno
+        for (ret, errno, status) in [
+            (0, 0, -libc::EOPNOTSUPP),
+            (-1, libc::EOPNOTSUPP, 0),
+            (-1, libc::ENOTTY, 0),
+        ] {
+            assert_eq!(
+                dedupe_reply_to(ret, errno, status, 0)
+                    .unwrap_err()
+                    .to_string(),
+                "dest: filesystem does not support reflinks"
+            );
+        }
+        for (ret, errno, status) in [(0, 0, -libc::EXDEV), (-1, libc::EXDEV, 0)] {
+            assert_eq!(
+                dedupe_reply_to(ret, errno, status, 0)
+                    .unwrap_err()
+                    .to_string(),
+                "Cannot reflink dest to src across filesystems"
+            );
+        }
+        let err = dedupe_reply_to(-1, libc::EPERM, 0, 0).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<std::io::Error>()
+                .and_then(std::io::Error::raw_os_error),
+            Some(libc::EPERM)
+        );
+        assert_eq!(
+            dedupe_reply_to(0, 0, 0, 0).unwrap_err().to_string(),
+            "dest: no progress deduping at offset 4096"
+        );
+    }
+
+    /// Dedupes a copy of a file spanning several chunks under `dir`, and then a file differing
+    /// in its last byte. Returns false when the filesystem does not support reflinks
+    fn dedupes_under(dir: &Path) -> bool {
+        let contents = (0..DEDUPE_CHUNK_SIZE + 4096)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let mut changed = contents.clone();
+        *changed.last_mut().unwrap() ^= 1;
+        let (src, dest, other) = (dir.join("src"), dir.join("dest"), dir.join("other"));
+        std::fs::write(&src, &contents).unwrap();
+        std::fs::write(&dest, &contents).unwrap();
+        std::fs::write(&other, &changed).unwrap();
+        let ino = std::fs::metadata(&dest).unwrap().ino();
+
+        match dedupe_file(&src, &dest) {
+            Err(e) if e.to_string().ends_with("does not support reflinks") => {
+                assert_eq!(std::fs::read(&dest).unwrap(), contents);
+                return false;
+            }
+            result => result.unwrap(),
+        }
+        assert_eq!(std::fs::metadata(&dest).unwrap().ino(), ino);
+        assert_eq!(std::fs::read(&dest).unwrap(), contents);
+
+        let err = dedupe_file(&src, &other).unwrap_err();
+        assert!(err.to_string().contains("differ at offset"), "{err}");
+        assert_eq!(std::fs::read(&other).unwrap(), changed);
+        true
+    }
+
+    #[test]
+    fn dedupes_or_reports_reflinks_unsupported() {
+        let dir = tempfile::tempdir().unwrap();
+        if !dedupes_under(dir.path()) {
+            eprintln!("reflinks unsupported under {}", dir.path().display());
+        }
+    }
+
+    #[test]
+    fn dedupe_refuses_files_of_different_sizes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dest) = (dir.path().join("src"), dir.path().join("dest"));
+        std::fs::write(&src, b"abc").unwrap();
+        std::fs::write(&dest, b"abcd").unwrap();
+        let err = dedupe_file(&src, &dest).unwrap_err();
+        assert!(err.to_string().ends_with("differ in size"), "{err}");
+    }
+
+    /// Filesystem image mounted over a loop device for the duration of a test
+    struct Loopback {
+        dir: tempfile::TempDir,
+    }
+
+    impl Loopback {
+        /// None when the image cannot be made or mounted, for want of mkfs or privileges
+        fn mount(fs_type: &str) -> Option<Self> {
+            use std::process::{Command, Stdio};
+            let quiet = |command: &mut Command| {
+                command
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status()
+                    .is_ok_and(|status| status.success())
+            };
+            let dir = tempfile::tempdir().ok()?;
+            let image = dir.path().join("image");
+            // Sparse, and large enough for XFS
+            std::fs::File::create(&image)
                 .ok()?
-                .into_path();
-            (!path.is_symlink() && !path.is_dir()).then_some(path)
-        })
+                .set_len(320 << 20)
+                .ok()?;
+            std::fs::create_dir(dir.path().join("mnt")).ok()?;
+            let mounted = quiet(
+                Command::new(format!("mkfs.{fs_type}"))
+                    .arg("-q")
+                    .arg(&image),
+            ) && quiet(
+                Command::new("mount")
+                    .args(["-o", "loop"])
+                    .arg(&image)
+                    .arg(dir.path().join("mnt")),
+            );
+            mounted.then(|| Self { dir })
+        }
+
+        fn path(&self) -> PathBuf {
+            self.dir.path().join("mnt")
+        }
+    }
+
+    impl Drop for Loopback {
+        fn drop(&mut self) {
+            let _ = std::process::Command::new("umount")
+                .arg(self.path())
+                .status();
+        }
+    }
+
+    #[test]
+    fn dedupes_on_loopback_btrfs_and_xfs() {
+        for fs_type in ["btrfs", "xfs"] {
+            let Some(loopback) = Loopback::mount(fs_type) else {
+                eprintln!("skipping {fs_type}: cannot mount a loopback image");
+                continue;
+            };
+            if !dedupes_under(&loopback.path()) {
+                eprintln!("skipping {fs_type}: reflinks unsupported");
+            }
+        }
+    }
+
+    fn globs(patterns: &[&str]) -> Globs {
+        let patterns = patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        Globs::new(&patterns).unwrap()
+    }
+
+    #[test]
+    fn globs_without_slash_match_at_any_depth() {
+        let globs = globs(&["*.tmp", ".DS_Store"]);
+        assert!(globs.is_match(Path::new("a.tmp"), false));
+        assert!(globs.is_match(Path::new("x/y/a.tmp"), false));
+        assert!(globs.is_match(Path::new("x/.DS_Store"), false));
+        assert!(!globs.is_match(Path::new("a.tmp.bak"), false));
+    }
+
+    #[test]
+    fn globs_with_slash_are_anchored() {
+        let globs = globs(&["src/*.rs", "/docs/**", "./build/out"]);
+        assert!(globs.is_match(Path::new("src/main.rs"), false));
+        assert!(!globs.is_match(Path::new("src/sub/main.rs"), false));
+        assert!(!globs.is_match(Path::new("x/src/main.rs"), false));
+        assert!(globs.is_match(Path::new("docs/a/b.md"), false));
+        assert!(globs.is_match(Path::new("build/out"), false));
+        assert!(!globs.is_match(Path::new("x/build/out"), false));
+    }
+
+    #[test]
+    fn globs_ending_in_slash_match_directories_only() {
+        let globs = globs(&[".git/", "node_modules/", "vendor/lib/"]);
+        assert!(globs.is_match(Path::new(".git"), true));
+        assert!(globs.is_match(Path::new("sub/.git"), true));
+        // Worktrees and submodules have a .git file
+        assert!(!globs.is_match(Path::new("sub/.git"), false));
+        assert!(globs.is_match(Path::new("a/node_modules/x/index.js"), false));
+        assert!(globs.is_match(Path::new("vendor/lib"), true));
+        assert!(!globs.is_match(Path::new("x/vendor/lib"), true));
+    }
+
+    #[test]
+    fn invalid_globs_are_refused() {
+        let err = Globs::new(&["a[".to_string()]).unwrap_err();
+        assert!(err.to_string().starts_with("Invalid glob a["), "{err}");
+    }
+
+    #[test]
+    fn excludes_win_over_includes_for_files_only() {
+        let walker = Walker {
+            include: Some(globs(&["*.jpg"])),
+            exclude: globs(&["thumbs/"]),
+            ..Walker::default()
+        };
+        assert!(walker.admits(Path::new("a/b.jpg"), false));
+        assert!(!walker.admits(Path::new("a/b.png"), false));
+        assert!(walker.admits(Path::new("a"), true));
+        assert!(!walker.admits(Path::new("a/thumbs"), true));
+        assert!(!walker.admits(Path::new("a/thumbs/b.jpg"), false));
+    }
+
+    #[test]
+    fn relative_targets_climb_to_the_common_ancestor() {
+        let rel = |path, base| relative_to(Path::new(path), Path::new(base));
+        assert_eq!(rel("/a/b/c", "/a/b"), Path::new("c"));
+        assert_eq!(rel("/a/b/c", "/a/d"), Path::new("../b/c"));
+        assert_eq!(rel("/a/b/c", "/a/d/e"), Path::new("../../b/c"));
+        assert_eq!(rel("/a/b", "/x/y"), Path::new("../../a/b"));
+    }
+
+    #[test]
+    fn sizes_take_binary_unit_suffixes() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size(" 4k "), Ok(4096));
+        assert_eq!(parse_size("2KiB"), Ok(2048));
+        assert_eq!(parse_size("3mb"), Ok(3 << 20));
+        assert_eq!(parse_size("1.5G"), Ok(3 << 29));
+        assert_eq!(parse_size("1t"), Ok(1 << 40));
+        assert_eq!(parse_size("0"), Ok(0));
+    }
+
+    #[test]
+    fn sizes_that_are_not_byte_counts_are_refused() {
+        for size in ["", "k", "-1", "-1k", "4x", "1k2", "nan", "inf"] {
+            let err = parse_size(size).unwrap_err();
+            assert!(err.starts_with(&format!("invalid size {size},")), "{err}");
+        }
+    }
+
+    #[test]
+    fn skipped_dirs_are_not_walked() {
+        let dir = tempfile::tempdir().unwrap();
+        for file in ["a", "q/tree/a", "sub/b"] {
+            let path = dir.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "same").unwrap();
+        }
+        let mut walker = Walker::default();
+        walker.skip_dir(&dir.path().join("q")).unwrap();
+        let mut walked = dir.path().walkdir(&walker).collect::<Vec<_>>();
+        walked.sort();
+        assert_eq!(walked, [dir.path().join("a"), dir.path().join("sub/b")]);
+    }
+
+    /// Files of the tree under `dir`, as name and contents, and symlinks as name and target
+    fn tree(dir: &Path, files: &[(&str, &str)], links: &[(&str, &str)]) {
+        for (file, contents) in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        for (link, target) in links {
+            std::os::unix::fs::symlink(target, dir.join(link)).unwrap();
+        }
+    }
+
+    /// Paths walked under `root`, relative to `base` and sorted
+    fn walked(root: &Path, base: &Path, walker: &Walker) -> Vec<PathBuf> {
+        let mut walked = root
+            .walkdir(walker)
+            .map(|path| path.strip_prefix(base).unwrap().to_path_buf())
+            .collect::<Vec<_>>();
+        walked.sort();
+        walked
+    }
+
+    fn following() -> Walker {
+        Walker {
+            follow_symlinks: true,
+            ..Walker::default()
+        }
+    }
+
+    #[test]
+    fn symlinks_are_skipped_unless_followed() {
+        let dir = tempfile::tempdir().unwrap();
+        tree(
+            dir.path(),
+            &[("t/f", "1"), ("o/g", "2")],
+            &[("t/l", "../o")],
+        );
+        let root = dir.path().join("t");
+        assert_eq!(walked(&root, &root, &Walker::default()), [Path::new("f")]);
+        assert_eq!(
+            walked(&root, &root, &following()),
+            [Path::new("f"), Path::new("l/g")]
+        );
+    }
+
+    #[test]
+    fn loops_are_walked_once() {
+        let dir = tempfile::tempdir().unwrap();
+        tree(
+            dir.path(),
+            &[("a/f", "1")],
+            &[("a/self", "."), ("a/up", ".."), ("a/b", ".")],
+        );
+        let root = dir.path().join("a");
+        assert_eq!(walked(&root, &root, &following()), [Path::new("f")]);
+    }
+
+    #[test]
+    fn directories_linked_twice_are_walked_once() {
+        let dir = tempfile::tempdir().unwrap();
+        tree(
+            dir.path(),
+            &[("t/f", "1"), ("o/g", "2")],
+            &[("t/l1", "../o"), ("t/l2", "../o")],
+        );
+        let root = dir.path().join("t");
+        let walked = walked(&root, &root, &following());
+        assert_eq!(walked.len(), 2, "{walked:?}");
+        assert_eq!(walked[0], Path::new("f"));
+        assert!(walked[1].ends_with("g"), "{walked:?}");
+    }
+
+    #[test]
+    fn files_are_walked_at_their_own_path_over_links_to_them() {
+        let dir = tempfile::tempdir().unwrap();
+        // Links that sort before their targets, which are walked after them
+        tree(
+            dir.path(),
+            &[("t/z/f", "1"), ("t/zz", "2")],
+            &[("t/a", "z"), ("t/b", "zz")],
+        );
+        let root = dir.path().join("t");
+        assert_eq!(
+            walked(&root, &root, &following()),
+            [Path::new("z/f"), Path::new("zz")]
+        );
+    }
+
+    #[test]
+    fn files_walked_by_one_walk_are_skipped_by_the_next() {
+        let dir = tempfile::tempdir().unwrap();
+        tree(
+            dir.path(),
+            &[("r1/d/f", "1"), ("r2/g", "2")],
+            &[("r2/l", "../r1/d")],
+        );
+        let walker = following();
+        let (r1, r2) = (dir.path().join("r1"), dir.path().join("r2"));
+        assert_eq!(walked(&r1, dir.path(), &walker), [Path::new("r1/d/f")]);
+        assert_eq!(walked(&r2, dir.path(), &walker), [Path::new("r2/g")]);
+        // Walks of a fresh walker start over
+        assert_eq!(
+            walked(&r2, dir.path(), &following()),
+            [Path::new("r2/g"), Path::new("r2/l/f")]
+        );
+    }
+
+    #[test]
+    fn ignore_files_are_read_only_when_respected() {
+        let dir = tempfile::tempdir().unwrap();
+        tree(
+            dir.path(),
+            &[
+                (".gitignore", "*.log\n"),
+                (".ignore", "!kept.log\n*.tmp\n"),
+                (".dedupignore", "!kept.tmp\n"),
+                ("a.log", "1"),
+                ("kept.log", "2"),
+                ("a.tmp", "3"),
+                ("kept.tmp", "4"),
+                ("sub/.gitignore", "!nested.log\n"),
+                ("sub/nested.log", "5"),
+                ("sub/b.log", "6"),
+            ],
+            &[],
+        );
+        let root = dir.path();
+        assert_eq!(walked(root, root, &Walker::default()).len(), 10);
+
+        let walker = Walker {
+            respect_ignores: true,
+            ..Walker::default()
+        };
+        // .dedupignore wins over .ignore, which wins over .gitignore, and nested files over
+        // those above them
+        assert_eq!(
+            walked(root, root, &walker),
+            [
+                ".dedupignore",
+                ".gitignore",
+                ".ignore",
+                "kept.log",
+                "kept.tmp",
+                "sub/.gitignore",
+                "sub/nested.log",
+            ]
+            .map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn blocked_directories_are_not_walked() {
+        let dir = tempfile::tempdir().unwrap();
+        tree(
+            dir.path(),
+            &[("a", "1"), ("mnt/b", "2"), ("sub/c", "3")],
+            &[],
+        );
+        let opts = WalkOpts {
+            mount_block: vec![dir.path().join("mnt")],
+            ..WalkOpts::default()
+        };
+        let root = dir.path();
+        assert_eq!(
+            walked(root, root, &opts.walker().unwrap()),
+            ["a", "sub/c"].map(PathBuf::from)
+        );
+
+        let opts = WalkOpts {
+            mount_block: vec![dir.path().join("a")],
+            ..WalkOpts::default()
+        };
+        let err = opts.walker().unwrap_err();
+        assert!(err.to_string().ends_with("is not a directory"), "{err}");
+    }
+
+    #[test]
+    fn other_filesystems_are_only_entered_when_allowed() {
+        let dir = tempfile::tempdir().unwrap();
+        let md = std::fs::metadata(dir.path()).unwrap();
+        let elsewhere = Some(md.dev() + 1);
+        let mut walker = Walker {
+            one_file_system: true,
+            ..Walker::default()
+        };
+        assert!(walker.admits_dir(dir.path(), &md, Some(md.dev())));
+        assert!(!walker.admits_dir(dir.path(), &md, elsewhere));
+        walker.allowed_devices.insert(md.dev());
+        assert!(walker.admits_dir(dir.path(), &md, elsewhere));
+        // Blocked directories stay so even on allowed filesystems
+        walker.blocked_dirs.insert((md.dev(), md.ino()));
+        assert!(!walker.admits_dir(dir.path(), &md, Some(md.dev())));
     }
 }
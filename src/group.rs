@@ -0,0 +1,202 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use log::debug;
+use std::collections::BTreeMap;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use tokio::fs::metadata;
+
+/// Policy deciding which copy of a duplicate group survives
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeepPolicy {
+    /// Keep the copy with the oldest modification time
+    OldestMtime,
+    /// Keep the copy with the newest modification time
+    NewestMtime,
+    /// Keep the copy with the shortest path
+    ShortestPath,
+    /// Keep the copy with the longest path
+    LongestPath,
+    /// Keep the copy whose path sorts first
+    Alphabetical,
+    /// Keep the copy under the root given first, alphabetically within a root
+    #[default]
+    FirstInPathOrder,
+}
+
+/// Splits groups of identical files into a survivor and the duplicates to act on
+#[derive(Debug)]
+pub struct Resolver {
+    keep: KeepPolicy,
+    roots: Vec<PathBuf>,
+    /// Whether survivors must share the filesystem of their duplicates
+    per_device: bool,
+}
+
+impl Resolver {
+    /// `roots` are the trees being deduped, in the order given by the user. With `per_device`,
+    /// groups are split by filesystem before picking survivors, as links cannot cross them
+    pub fn new(keep: KeepPolicy, roots: Vec<PathBuf>, per_device: bool) -> Self {
+        Self {
+            keep,
+            roots,
+            per_device,
+        }
+    }
+
+    /// Splits `group` by the device ID of its files when survivors must share their
+    /// filesystem, or returns it whole
+    pub async fn split(&self, group: Vec<PathBuf>) -> Result<Vec<Vec<PathBuf>>> {
+        if !self.per_device {
+            return Ok(vec![group]);
+        }
+        let mut by_device: BTreeMap<u64, Vec<PathBuf>> = BTreeMap::new();
+        for path in group {
+            let dev = metadata(&path).await?.dev();
+            by_device.entry(dev).or_default().push(path);
+        }
+        if by_device.len() > 1 {
+            debug!(
+                "Group spans {} filesystems, deduping each on its own",
+                by_device.len()
+            );
+        }
+        Ok(by_device.into_values().collect())
+    }
+
+    /// Returns the first of the roots containing `path`
+    pub fn root_of(&self, path: &Path) -> Result<&Path> {
+        self.roots
+            .iter()
+            .find(|root| path.starts_with(root))
+            .map(PathBuf::as_path)
+            .ok_or_else(|| anyhow::anyhow!("{} lies under none of the roots", path.display()))
+    }
+
+    /// Returns the survivor of `group` and the remaining duplicates. Ties are broken by path
+    pub async fn resolve(&self, mut group: Vec<PathBuf>) -> Result<(PathBuf, Vec<PathBuf>)> {
+        anyhow::ensure!(!group.is_empty(), "Cannot resolve an empty group");
+        group.sort();
+
+        match self.keep {
+            KeepPolicy::OldestMtime | KeepPolicy::NewestMtime => {
+                let mut keyed = Vec::with_capacity(group.len());
+                for path in group {
+                    let mtime = metadata(&path).await?.modified()?;
+                    keyed.push((mtime, path));
+                }
+                // Stable sort keeps the alphabetical order among equal mtimes
+                if self.keep == KeepPolicy::OldestMtime {
+                    keyed.sort_by_key(|(mtime, _)| *mtime);
+                } else {
+                    keyed.sort_by_key(|(mtime, _)| std::cmp::Reverse(*mtime));
+                }
+                group = keyed.into_iter().map(|(_, path)| path).collect();
+            }
+            KeepPolicy::ShortestPath => group.sort_by_key(|path| path.as_os_str().len()),
+            KeepPolicy::LongestPath => {
+                group.sort_by_key(|path| std::cmp::Reverse(path.as_os_str().len()))
+            }
+            KeepPolicy::Alphabetical => {}
+            KeepPolicy::FirstInPathOrder => group.sort_by_key(|path| {
+                self.roots
+                    .iter()
+                    .position(|root| path.starts_with(root))
+                    .unwrap_or(self.roots.len())
+            }),
+        }
+
+        let survivor = group.remove(0);
+        Ok((survivor, group))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    async fn survivor(keep: KeepPolicy, roots: &[&str], group: &[&str]) -> PathBuf {
+        let resolver = Resolver::new(keep, paths(roots), false);
+        let (survivor, duplicates) = resolver.resolve(paths(group)).await.unwrap();
+        assert_eq!(duplicates.len(), group.len() - 1);
+        assert!(!duplicates.contains(&survivor));
+        survivor
+    }
+
+    #[tokio::test]
+    async fn keeps_by_path() {
+        let group = ["b/long/name", "a/x", "c/yy"];
+        let cases = [
+            (KeepPolicy::ShortestPath, "a/x"),
+            (KeepPolicy::LongestPath, "b/long/name"),
+            (KeepPolicy::Alphabetical, "a/x"),
+        ];
+        for (keep, expected) in cases {
+            assert_eq!(
+                survivor(keep, &[], &group).await,
+                Path::new(expected),
+                "{keep:?}"
+            );
+        }
+        // Ties are broken alphabetically
+        let tied = ["b/y", "a/z", "c/x"];
+        assert_eq!(
+            survivor(KeepPolicy::ShortestPath, &[], &tied).await,
+            Path::new("a/z")
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_first_in_path_order() {
+        let group = ["a/1", "z/1", "m/1", "elsewhere/1"];
+        let keep = KeepPolicy::FirstInPathOrder;
+        assert_eq!(survivor(keep, &["z", "a"], &group).await, Path::new("z/1"));
+        assert_eq!(survivor(keep, &["m", "z"], &group).await, Path::new("m/1"));
+        let within = ["r/b", "r/a"];
+        assert_eq!(survivor(keep, &["r"], &within).await, Path::new("r/a"));
+    }
+
+    #[tokio::test]
+    async fn keeps_by_mtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = SystemTime::now();
+        let mut group = Vec::new();
+        for (name, age) in [("a", 10), ("b", 30), ("c", 20), ("d", 30)] {
+            let path = dir.path().join(name);
+            let file = std::fs::File::create(&path).unwrap();
+            file.set_modified(now - Duration::from_secs(age)).unwrap();
+            group.push(path.to_str().unwrap().to_string());
+        }
+        let group = group.iter().map(String::as_str).collect::<Vec<_>>();
+        // b and d are as old, and b sorts first
+        assert_eq!(
+            survivor(KeepPolicy::OldestMtime, &[], &group).await,
+            dir.path().join("b")
+        );
+        assert_eq!(
+            survivor(KeepPolicy::NewestMtime, &[], &group).await,
+            dir.path().join("a")
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_empty_groups() {
+        let resolver = Resolver::new(KeepPolicy::default(), Vec::new(), false);
+        assert!(resolver.resolve(Vec::new()).await.is_err());
+    }
+
+    #[test]
+    fn finds_the_first_root_of_a_path() {
+        let resolver = Resolver::new(KeepPolicy::default(), paths(&["a", "a/b"]), false);
+        assert_eq!(
+            resolver.root_of(Path::new("a/b/c")).unwrap(),
+            Path::new("a")
+        );
+        assert!(resolver.root_of(Path::new("ab/c")).is_err());
+    }
+}
@@ -1,33 +1,203 @@
+use crate::cache;
 use anyhow::Result;
-use twox_hash::XxHash3_64;
-use std::hash::Hasher;
-use std::path::Path;
+use clap::ValueEnum;
+use log::trace;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::fmt;
 use std::fs::OpenOptions;
+use std::hash::Hasher;
 use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use twox_hash::{XxHash3_64, XxHash3_128};
+
+/// Seed of the xxh3 hashers
+pub const SEED: u64 = 0xdeadbeef;
+
+/// Hashing algorithm used for checksumming
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum HashAlgo {
+    /// 64-bit xxh3, seeded. Fastest, fine for trees of up to millions of files
+    #[default]
+    #[value(name = "xxh3-64")]
+    Xxh3_64,
+    /// 128-bit xxh3, seeded
+    #[value(name = "xxh3-128")]
+    Xxh3_128,
+    /// BLAKE3, as computed by b3sum
+    Blake3,
+    /// SHA-256, as computed by sha256sum
+    Sha256,
+    /// MD5, as computed by md5sum
+    Md5,
+}
+
+impl HashAlgo {
+    /// Parses the name an algorithm is displayed with
+    pub fn from_name(name: &str) -> Result<Self> {
+        <Self as ValueEnum>::from_str(name, true)
+            .map_err(|_| anyhow::anyhow!("Unknown hash algorithm {name}"))
+    }
+
+    /// Seed the algorithm is computed with, for those that take one
+    pub fn seed(&self) -> Option<u64> {
+        match self {
+            HashAlgo::Xxh3_64 | HashAlgo::Xxh3_128 => Some(SEED),
+            HashAlgo::Blake3 | HashAlgo::Sha256 | HashAlgo::Md5 => None,
+        }
+    }
+
+    /// Checks that `chksum` could have been computed by this algorithm, and brings it to the
+    /// case it is computed in, as other tools may print hex digits in either case
+    pub fn normalize(&self, chksum: &str) -> Result<String> {
+        let valid_len = match self {
+            HashAlgo::Xxh3_64 => (1..=16).contains(&chksum.len()),
+            HashAlgo::Xxh3_128 | HashAlgo::Md5 => chksum.len() == 32,
+            HashAlgo::Blake3 | HashAlgo::Sha256 => chksum.len() == 64,
+        };
+        if !valid_len || !chksum.bytes().all(|b| b.is_ascii_hexdigit()) {
+            anyhow::bail!("{chksum} is not a valid {self} checksum");
+        }
+        Ok(match self {
+            HashAlgo::Xxh3_64 => chksum.to_ascii_uppercase(),
+            _ => chksum.to_ascii_lowercase(),
+        })
+    }
+
+    /// Number of bytes of the raw digests of the algorithm
+    pub fn digest_len(&self) -> usize {
+        match self {
+            HashAlgo::Xxh3_64 => 8,
+            HashAlgo::Xxh3_128 | HashAlgo::Md5 => 16,
+            HashAlgo::Blake3 | HashAlgo::Sha256 => 32,
+        }
+    }
+
+    /// Decodes the hex `chksum` into a raw digest of [`Self::digest_len`] bytes
+    pub fn to_digest(&self, chksum: &str) -> Result<Vec<u8>> {
+        let chksum = self.normalize(chksum)?;
+        Ok(match self {
+            // Written unpadded, so leading zeroes are missing
+            HashAlgo::Xxh3_64 => u64::from_str_radix(&chksum, 16)?.to_be_bytes().to_vec(),
+            _ => hex::decode(chksum)?,
+        })
+    }
+
+    /// Encodes a raw digest as the hex checksum computed by the algorithm
+    pub fn from_digest(&self, digest: &[u8]) -> Result<String> {
+        anyhow::ensure!(
+            digest.len() == self.digest_len(),
+            "{} byte digest is not a {self} digest",
+            digest.len()
+        );
+        Ok(match self {
+            HashAlgo::Xxh3_64 => format!("{:X}", u64::from_be_bytes(digest.try_into()?)),
+            _ => hex::encode(digest),
+        })
+    }
+}
+
+impl fmt::Display for HashAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self.to_possible_value().expect("no skipped variants");
+        f.write_str(value.get_name())
+    }
+}
+
+/// Hashing state of a checksum being computed
+enum State {
+    Xxh3_64(XxHash3_64),
+    Xxh3_128(XxHash3_128),
+    Blake3(Box<blake3::Hasher>),
+    Sha256(Sha256),
+    Md5(Md5),
+}
+
+impl State {
+    fn new(algo: HashAlgo) -> Self {
+        match algo {
+            HashAlgo::Xxh3_64 => State::Xxh3_64(XxHash3_64::with_seed(SEED)),
+            HashAlgo::Xxh3_128 => State::Xxh3_128(XxHash3_128::with_seed(SEED)),
+            HashAlgo::Blake3 => State::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgo::Sha256 => State::Sha256(Sha256::new()),
+            HashAlgo::Md5 => State::Md5(Md5::new()),
+        }
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        match self {
+            State::Xxh3_64(h) => h.write(buf),
+            State::Xxh3_128(h) => h.write(buf),
+            State::Blake3(h) => {
+                h.update(buf);
+            }
+            State::Sha256(h) => h.update(buf),
+            State::Md5(h) => h.update(buf),
+        }
+    }
+
+    /// xxh3-64 keeps the unpadded upper case hex it always had, so older analysis files still
+    /// match. The others are formatted like the coreutils and b3sum tools print them
+    fn finish(self) -> String {
+        match self {
+            State::Xxh3_64(h) => format!("{:X}", h.finish()),
+            State::Xxh3_128(h) => format!("{:032x}", h.finish_128()),
+            State::Blake3(h) => h.finalize().to_hex().to_string(),
+            State::Sha256(h) => hex::encode(h.finalize()),
+            State::Md5(h) => hex::encode(h.finalize()),
+        }
+    }
+}
 
 pub trait HashFile: AsRef<Path> {
-    fn chksum(&self) -> Result<(usize, String)>;
+    fn chksum(&self) -> Result<(usize, String)> {
+        self.chksum_with(HashAlgo::default())
+    }
+
+    fn chksum_with(&self, algo: HashAlgo) -> Result<(usize, String)>;
 }
 
 impl<P> HashFile for P
-where 
+where
     P: AsRef<Path>,
 {
-    fn chksum(&self) -> Result<(usize, String)> {
-        let mut sh = XxHash3_64::with_seed(0xdeadbeef);
+    fn chksum_with(&self, algo: HashAlgo) -> Result<(usize, String)> {
+        let mut sh = State::new(algo);
         let capacity = 256 * 1024; // 256 KB
-        let inner = OpenOptions::new().read(true).write(false).create(false).open(self)?;
+        let inner = OpenOptions::new()
+            .read(true)
+            .write(false)
+            .create(false)
+            .open(self)?;
         let mut br = BufReader::with_capacity(capacity, inner);
         let mut file_size = 0;
         loop {
             let buf = br.fill_buf()?;
-            if buf.is_empty() { break; }
+            if buf.is_empty() {
+                break;
+            }
             let buflen = buf.len();
             file_size += buflen;
-            sh.write(buf);
+            sh.update(buf);
             br.consume(buflen);
         }
 
-        Ok((file_size, format!("{:X}", sh.finish())))
+        Ok((file_size, sh.finish()))
     }
 }
+
+/// Hashes `path` on the blocking thread pool, unless the hash cache holds a checksum computed
+/// since the file last changed
+pub async fn cached_chksum(path: PathBuf, algo: HashAlgo) -> Result<(usize, String)> {
+    let md = tokio::fs::metadata(&path).await?;
+    if let Some(hit) = cache::cached(&path, &md, algo) {
+        trace!("{}: using cached {algo} chksum", path.display());
+        return Ok(hit);
+    }
+
+    let path_clone = path.clone();
+    let (size, chksum) =
+        tokio::task::spawn_blocking(move || path_clone.chksum_with(algo)).await??;
+    cache::remember(&path, &md, algo, size, chksum.clone());
+    Ok((size, chksum))
+}
@@ -1,11 +1,271 @@
+use crate::action::{Duplicate, Executor, Outcome, Summary};
+use crate::fs::{DirOps, Walker};
+use crate::hasher::{HashAlgo, cached_chksum};
+use anyhow::Result;
 use clap::Args;
-use std::path::PathBuf;
+use futures::{StreamExt, stream};
+use log::{debug, error, info, trace, warn};
+use std::{collections::HashMap, path::PathBuf};
+use tokio::fs::{canonicalize, metadata};
 
 #[derive(Args, Debug)]
 #[command(arg_required_else_help = true)]
 /// Dedups files in a folder in-place
 pub struct InPlace {
-    /// Local Path containing files that need to be checked for duplicates in-place
+    /// Local Paths containing files that need to be checked for duplicates in-place. Can be
+    /// repeated to dedup across several trees, in which case their order matters to `--keep`
     #[arg(short, long, default_value = ".")]
-    pub local_path: PathBuf,
+    pub local_path: Vec<PathBuf>,
+}
+
+impl InPlace {
+    pub async fn dedup(&self, exec: &Executor, algo: HashAlgo, walker: &Walker) -> Result<Summary> {
+        debug!("Starting in-place dedup at {:?}", self.local_path);
+
+        let mut canonical_roots: Vec<PathBuf> = Vec::with_capacity(self.local_path.len());
+        for root in &self.local_path {
+            if !root.exists() {
+                anyhow::bail!("Local path not found - {}", root.display());
+            }
+            let canonical = canonicalize(root).await?;
+            if let Some(other) = canonical_roots
+                .iter()
+                .find(|other| canonical.starts_with(other) || other.starts_with(&canonical))
+            {
+                anyhow::bail!(
+                    "{} and {} overlap. Pass only the outermost of nested paths.",
+                    root.display(),
+                    other.display()
+                );
+            }
+            canonical_roots.push(canonical);
+        }
+
+        let resolver = exec.resolver(self.local_path.clone());
+        let entries = self.local_path.iter().flat_map(|root| root.walkdir(walker));
+        let mut stream = stream::iter(entries)
+            .map(move |path| async move {
+                metadata(&path)
+                    .await
+                    .inspect_err(|e| warn!("Error reading file info {}: {e}", path.display()))
+                    .map(|md| (md.len(), path))
+                    .ok()
+            })
+            .buffer_unordered(num_cpus::get() * 4);
+
+        let mut summary = Summary::default();
+        let mut size_map: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+        while let Some(entry) = stream.next().await {
+            let Some((size, path)) = entry else { continue };
+            summary.processed += 1;
+            size_map.entry(size).or_default().push(path);
+        }
+
+        // Only files sharing their size with another file can have duplicates
+        let candidates = size_map
+            .into_values()
+            .filter(|paths| paths.len() > 1)
+            .flatten()
+            .collect::<Vec<_>>();
+        info!(
+            "{} files found, {} of them share their size with another file",
+            summary.processed,
+            candidates.len()
+        );
+
+        let mut stream = stream::iter(candidates)
+            .map(move |file_path| async move {
+                debug!("Start analyzing file: {}", file_path.display());
+                let file_path_clone = file_path.clone();
+                async {
+                    let (size, chksum) = cached_chksum(file_path_clone, algo).await?;
+                    debug!("Finished analyzing file: {}", file_path.display());
+                    Ok::<_, anyhow::Error>((size, chksum))
+                }
+                .await
+                .inspect_err(|e| warn!("Error hashing file {}: {e}", file_path.display()))
+                .map(|(size, chksum)| (size, chksum, file_path))
+                .ok()
+            })
+            .buffer_unordered(num_cpus::get() * 2);
+
+        let mut groups: HashMap<(usize, String), Vec<PathBuf>> = HashMap::new();
+        while let Some(entry) = stream.next().await {
+            let Some((size, chksum, file_path)) = entry else {
+                continue;
+            };
+            groups.entry((size, chksum)).or_default().push(file_path);
+        }
+
+        // Links cannot cross filesystems, so each keeps a survivor of its own
+        let mut linkable = Vec::new();
+        for ((size, chksum), group) in groups {
+            if group.len() < 2 {
+                continue;
+            }
+            match resolver.split(group).await {
+                Ok(split) => {
+                    linkable.extend(split.into_iter().map(|group| (size, chksum.clone(), group)))
+                }
+                Err(e) => error!("Error splitting size={size} chksum={chksum} by filesystem: {e}"),
+            }
+        }
+
+        for (size, chksum, group) in linkable {
+            if group.len() < 2 {
+                continue;
+            }
+
+            let num_copies = group.len();
+            let (survivor, duplicates) = match resolver.resolve(group).await {
+                Ok(ok) => ok,
+                Err(e) => {
+                    error!("Error picking survivor of size={size} chksum={chksum}: {e}");
+                    continue;
+                }
+            };
+            trace!(
+                "Found {num_copies} copies of size={size} chksum={chksum}, keeping {}",
+                survivor.display()
+            );
+
+            for file_path in duplicates {
+                let root = match resolver.root_of(&file_path) {
+                    Ok(root) => root,
+                    Err(e) => {
+                        error!("error processing file {}: {e}", file_path.display());
+                        continue;
+                    }
+                };
+                let duplicate = Duplicate {
+                    path: &file_path,
+                    root,
+                    survivor: Some(&survivor),
+                    reference: None,
+                    size,
+                    chksum: &chksum,
+                };
+                match exec.apply(&duplicate).await {
+                    Ok(outcome) => {
+                        summary.record(outcome);
+                        debug!(
+                            "successfully processed file {}, duplicate of {}",
+                            file_path.display(),
+                            survivor.display()
+                        );
+                    }
+                    Err(e) => {
+                        summary.record(Outcome::Failed);
+                        error!("error processing file {}: {e}", file_path.display());
+                    }
+                }
+            }
+        }
+
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::Action;
+    use crate::group::KeepPolicy;
+
+    /// A tree holding each file with its contents
+    fn tree(files: &[(&str, &str)]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (file, contents) in files {
+            let path = dir.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        dir
+    }
+
+    fn remaining(dir: &tempfile::TempDir, files: &[&str]) -> Vec<String> {
+        files
+            .iter()
+            .filter(|file| dir.path().join(file).exists())
+            .map(|file| file.to_string())
+            .collect()
+    }
+
+    async fn dedup(roots: Vec<PathBuf>, commit: bool) -> Result<Summary> {
+        let exec = Executor::new(commit, KeepPolicy::default(), Action::Delete, None, None);
+        InPlace { local_path: roots }
+            .dedup(&exec, HashAlgo::default(), &Walker::default())
+            .await
+    }
+
+    const FILES: [(&str, &str); 6] = [
+        ("a/1", "same"),
+        ("b/1", "same"),
+        ("b/2", "same"),
+        // Same size, other contents
+        ("c/1", "diff"),
+        ("c/2", "pair"),
+        ("d/2", "pair"),
+    ];
+    const NAMES: [&str; 6] = ["a/1", "b/1", "b/2", "c/1", "c/2", "d/2"];
+
+    #[tokio::test]
+    async fn keeps_one_copy_per_group_of_identical_files() {
+        let dir = tree(&FILES);
+        let summary = dedup(vec![dir.path().to_path_buf()], true).await.unwrap();
+        assert_eq!((summary.processed, summary.duplicates), (6, 3));
+        assert_eq!(remaining(&dir, &NAMES), ["a/1", "c/1", "c/2"]);
+    }
+
+    #[tokio::test]
+    async fn dry_runs_only_report_duplicates() {
+        let dir = tree(&FILES);
+        let summary = dedup(vec![dir.path().to_path_buf()], false).await.unwrap();
+        assert_eq!((summary.processed, summary.duplicates), (6, 3));
+        assert_eq!(remaining(&dir, &NAMES), NAMES);
+    }
+
+    #[tokio::test]
+    async fn groups_span_every_root_given() {
+        let dir = tree(&FILES);
+        let roots = vec![dir.path().join("d"), dir.path().join("c")];
+        let summary = dedup(roots, true).await.unwrap();
+        assert_eq!((summary.processed, summary.duplicates), (3, 1));
+        // The copy under the root given first survives
+        assert_eq!(remaining(&dir, &["c/1", "c/2", "d/2"]), ["c/1", "d/2"]);
+    }
+
+    #[tokio::test]
+    async fn failed_actions_are_not_counted_as_taken() {
+        let dir = tree(&FILES);
+        // Nothing can be moved under a regular file
+        let quarantine = dir.path().join("a/1");
+        let exec = Executor::new(
+            true,
+            KeepPolicy::default(),
+            Action::Quarantine(quarantine),
+            None,
+            None,
+        );
+        let summary = InPlace {
+            local_path: vec![dir.path().to_path_buf()],
+        }
+        .dedup(&exec, HashAlgo::default(), &Walker::default())
+        .await
+        .unwrap();
+        assert_eq!((summary.duplicates, summary.failed), (0, 3));
+        assert_eq!(remaining(&dir, &NAMES), NAMES);
+    }
+
+    #[tokio::test]
+    async fn overlapping_roots_are_refused() {
+        let dir = tree(&FILES);
+        for roots in [["b", ""], ["", "b"], ["b", "b"]] {
+            let roots = roots.iter().map(|root| dir.path().join(root)).collect();
+            let err = dedup(roots, true).await.unwrap_err();
+            assert!(err.to_string().contains("overlap"), "{err}");
+        }
+        assert!(dedup(vec![dir.path().join("missing")], true).await.is_err());
+        assert_eq!(remaining(&dir, &NAMES), NAMES);
+    }
 }
@@ -0,0 +1,255 @@
+use crate::hasher::HashAlgo;
+use crate::inventory::{Format, Header, Inventory, Layout, Record, write_inventory};
+use anyhow::Result;
+use clap::{Args, Subcommand, ValueEnum};
+use log::info;
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+/// Combines analysis files without scanning the trees they describe
+pub struct Inventories {
+    #[command(subcommand)]
+    pub command: InventoryCommand,
+
+    /// File to write the resulting analysis to, or `-` for stdout
+    #[arg(short, long, global = true, default_value = "-")]
+    pub output_file: PathBuf,
+
+    /// Encoding of the output
+    #[arg(short, long, global = true, value_enum, default_value_t)]
+    pub format: Format,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum InventoryCommand {
+    /// Writes the contents found in any of the inputs, as a single reference set
+    Merge {
+        #[arg(required = true, num_args = 2..)]
+        inputs: Vec<PathBuf>,
+    },
+    /// Writes the records of the first input whose contents none of the others hold
+    Diff {
+        #[arg(required = true, num_args = 2..)]
+        inputs: Vec<PathBuf>,
+    },
+    /// Writes the records of the first input whose contents all of the others hold
+    Intersect {
+        #[arg(required = true, num_args = 2..)]
+        inputs: Vec<PathBuf>,
+    },
+}
+
+impl Inventories {
+    /// Reads every input, which must share a hash algorithm, and writes the result. Inputs are
+    /// read in full, so `-` may stand for stdin once
+    pub async fn run(&self, requested: Option<HashAlgo>) -> Result<()> {
+        let (InventoryCommand::Merge { inputs }
+        | InventoryCommand::Diff { inputs }
+        | InventoryCommand::Intersect { inputs }) = &self.command;
+
+        let first = Inventory::read_all(&inputs[0], requested).await?;
+        let mut others = Vec::new();
+        for input in &inputs[1..] {
+            others.push(Inventory::read_all(input, Some(first.algo)).await?);
+        }
+
+        let (layout, mut records) = match self.command {
+            InventoryCommand::Merge { .. } => {
+                // Contents are what the inputs share, so their per-file records are dropped
+                let records = std::iter::once(&first)
+                    .chain(&others)
+                    .flat_map(|inventory| inventory.records())
+                    .cloned()
+                    .collect();
+                (Layout::Sizes, records)
+            }
+            InventoryCommand::Diff { .. } => {
+                let records = kept(&first, |record| {
+                    !others.iter().any(|other| holds(other, record))
+                });
+                (layout_of(&first), records)
+            }
+            InventoryCommand::Intersect { .. } => {
+                let records = kept(&first, |record| {
+                    others.iter().all(|other| holds(other, record))
+                });
+                (layout_of(&first), records)
+            }
+        };
+
+        // Checksum listings name no sizes, which every record of these formats needs
+        if matches!(self.format, Format::Text | Format::Binary)
+            && let Some(record) = records.iter().find(|record| record.size.is_none())
+        {
+            anyhow::bail!(
+                "Checksum {} comes from a listing without sizes, which the {} format cannot hold. Use --format jsonl or csv",
+                record.chksum,
+                self.format.to_possible_value().unwrap().get_name()
+            );
+        }
+
+        // The binary format holds no per-file records
+        let layout = match self.format {
+            Format::Binary => Layout::Sizes,
+            _ => layout,
+        };
+        if layout == Layout::Sizes {
+            records = contents(records);
+        }
+        let num_chksums = records
+            .iter()
+            .map(|record| (record.size, &record.chksum))
+            .collect::<HashSet<_>>()
+            .len();
+        let num_files = match layout {
+            Layout::Files => records.len(),
+            Layout::Sizes => num_chksums,
+        };
+        let header = match self.command {
+            InventoryCommand::Merge { .. } => {
+                merged_header(&first, &others, num_files, num_chksums)
+            }
+            _ => derived_header(&first, layout, num_files, num_chksums),
+        };
+        info!(
+            "Writing {} records of {} inputs to {}",
+            records.len(),
+            inputs.len(),
+            self.output_file.display()
+        );
+        write_inventory(&header, &mut records, self.format, &self.output_file).await
+    }
+}
+
+/// Records of `inventory` satisfying `keep`
+fn kept(inventory: &Inventory, keep: impl Fn(&Record) -> bool) -> Vec<Record> {
+    inventory
+        .records()
+        .iter()
+        .filter(|record| keep(record))
+        .cloned()
+        .collect()
+}
+
+/// Distinct contents of `records`, without the files they were found in
+fn contents(records: Vec<Record>) -> Vec<Record> {
+    records
+        .into_iter()
+        .map(|record| Record {
+            mtime: None,
+            path: None,
+            ..record
+        })
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Whether `inventory` holds the contents of `record`. Records of unknown size match any size
+fn holds(inventory: &Inventory, record: &Record) -> bool {
+    match record.size {
+        Some(size) => inventory.contains(size, &record.chksum),
+        None => inventory.contains_chksum(&record.chksum),
+    }
+}
+
+/// Per-file analysis files keep their layout, any other input is reduced to its checksums
+fn layout_of(inventory: &Inventory) -> Layout {
+    match &inventory.header {
+        Some(header) => header.layout,
+        None => Layout::Sizes,
+    }
+}
+
+/// Header describing part of the tree `inventory` was made from
+fn derived_header(inventory: &Inventory, layout: Layout, files: usize, checksums: usize) -> Header {
+    let mut header = Header::new(layout, inventory.algo, PathBuf::from("-"), files, checksums);
+    match &inventory.header {
+        Some(source) => {
+            header.root = source.root.clone();
+            header.host = source.host.clone();
+            header.created = source.created;
+        }
+        // Checksum listings do not say where they were made
+        None => header.host = "-".to_string(),
+    }
+    header
+}
+
+/// Header of the union of `first` and `others`, naming their root and host only when they all
+/// agree, as the result describes no single tree otherwise
+fn merged_header(
+    first: &Inventory,
+    others: &[Inventory],
+    files: usize,
+    checksums: usize,
+) -> Header {
+    let mut header = derived_header(first, Layout::Sizes, files, checksums);
+    let sources = std::iter::once(first)
+        .chain(others)
+        .map(|inventory| &inventory.header);
+    let agreed = sources.clone().all(|source| {
+        source
+            .as_ref()
+            .is_some_and(|source| (&source.root, &source.host) == (&header.root, &header.host))
+    });
+    if !agreed {
+        header.root = PathBuf::from("-");
+        header.host = "-".to_string();
+    }
+    header.created = sources
+        .flatten()
+        .map(|source| source.created)
+        .min()
+        .unwrap_or(header.created);
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge(inputs: &[PathBuf], output_file: PathBuf, format: Format) -> Inventories {
+        Inventories {
+            command: InventoryCommand::Merge {
+                inputs: inputs.to_vec(),
+            },
+            output_file,
+            format,
+        }
+    }
+
+    #[tokio::test]
+    async fn listings_merge_only_into_formats_without_sizes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (md5, other) = (
+            "b1946ac92492d2347c6235b4d2611184",
+            "ba7790b1708b71cb2b61b1a30d824712",
+        );
+        let inputs = [dir.path().join("a.md5"), dir.path().join("b.md5")];
+        std::fs::write(&inputs[0], format!("{md5}  a\n")).unwrap();
+        std::fs::write(&inputs[1], format!("{other}  b\n")).unwrap();
+
+        for format in [Format::Text, Format::Binary] {
+            let output = dir.path().join("merged");
+            let err = merge(&inputs, output.clone(), format)
+                .run(Some(HashAlgo::Md5))
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("listing without sizes"), "{err}");
+            assert!(!output.exists());
+        }
+        for format in [Format::Jsonl, Format::Csv] {
+            let output = dir.path().join(format!("merged.{format:?}"));
+            merge(&inputs, output.clone(), format)
+                .run(Some(HashAlgo::Md5))
+                .await
+                .unwrap();
+            let merged = Inventory::read_all(&output, None).await.unwrap();
+            assert_eq!(merged.algo, HashAlgo::Md5);
+            assert!(merged.contains_chksum(md5) && merged.contains_chksum(other));
+        }
+    }
+}
@@ -0,0 +1,1635 @@
+use crate::binary::{self, Bytes, Packed};
+use crate::fs::{FileOps, replace_with, temp_sibling};
+use crate::hasher::HashAlgo;
+use anyhow::Result;
+use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+use async_compression::tokio::write::{GzipEncoder, ZstdEncoder};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fmt::{self, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
+};
+
+/// Version of the analysis file format written by this build, bumped whenever readers of older
+/// versions would misread its contents
+pub const FORMAT_VERSION: u32 = 3;
+
+/// Key of the header field holding the format version, which leads versioned analysis files
+const VERSION_KEY: &str = "dedup-analysis";
+
+/// First line of a versioned analysis file, followed by the format version
+const MAGIC: &str = "# dedup-analysis:";
+
+/// Header line recording the hash algorithm of an analysis file. Files written before the
+/// format was versioned carry it on its own
+pub const ALGO_HEADER: &str = "# hash-algo:";
+
+/// Columns of analysis files in CSV, in order
+const CSV_COLUMNS: [&str; 4] = ["size", "mtime", "chksum", "path"];
+
+/// Encoding of an analysis file
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    /// `# key: value` header lines, followed by `size:chksum,chksum` lines, or by tab separated
+    /// per-file records
+    #[default]
+    Text,
+    /// One JSON object per line, the header first
+    Jsonl,
+    /// `# key: value` header lines, followed by comma separated values under a row of column
+    /// names
+    Csv,
+    /// Sorted, delta-encoded blocks of raw digests, which remote mode searches without loading
+    /// them. Holds no per-file records
+    Binary,
+}
+
+/// What the records following the header of an analysis file describe
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Layout {
+    /// The distinct checksums found for each size
+    #[default]
+    Sizes,
+    /// Every file, with its path relative to the root and modification time
+    Files,
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self.to_possible_value().expect("no skipped variants");
+        f.write_str(value.get_name())
+    }
+}
+
+/// Self-description at the top of an analysis file, as `# key: value` lines or a JSON object
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub version: u32,
+    /// Name and version of the program that wrote the file
+    pub generator: String,
+    pub layout: Layout,
+    pub algo: HashAlgo,
+    /// Seed the checksums were computed with, for seeded algorithms
+    pub seed: Option<u64>,
+    /// Canonical path of the analyzed tree
+    pub root: PathBuf,
+    pub host: String,
+    pub created: DateTime<Utc>,
+    /// Number of files analyzed
+    pub files: usize,
+    /// Number of distinct checksums of each size, summed
+    pub checksums: usize,
+    /// Written as files were hashed, so the counts above follow the records rather than lead them
+    pub streamed: bool,
+}
+
+impl Header {
+    /// Describes an analysis of `root` on this host, made now
+    pub fn new(
+        layout: Layout,
+        algo: HashAlgo,
+        root: PathBuf,
+        files: usize,
+        checksums: usize,
+    ) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            generator: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            layout,
+            algo,
+            seed: algo.seed(),
+            root,
+            host: hostname(),
+            created: Utc::now(),
+            files,
+            checksums,
+            streamed: false,
+        }
+    }
+
+    /// Number of records following the header, to tell truncated files apart
+    pub fn records(&self) -> usize {
+        match self.layout {
+            Layout::Sizes => self.checksums,
+            Layout::Files => self.files,
+        }
+    }
+
+    /// Fields following the version, in the order they are written
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let created = self
+            .created
+            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        let mut fields = vec![
+            ("generator", self.generator.clone()),
+            ("layout", self.layout.to_string()),
+            ("hash-algo", self.algo.to_string()),
+            ("hash-seed", fmt_seed(self.seed)),
+            ("root", self.root.display().to_string()),
+            ("host", self.host.clone()),
+            ("created", created),
+        ];
+        if !self.streamed {
+            fields.extend(self.counts());
+        }
+        fields
+    }
+
+    /// Fields holding the number of files and checksums
+    fn counts(&self) -> [(&'static str, String); 2] {
+        [
+            ("files", self.files.to_string()),
+            ("checksums", self.checksums.to_string()),
+        ]
+    }
+
+    /// Takes the counts out of `fields`, which streamed files of version 3 and later lack
+    fn take_counts(
+        version: u32,
+        fields: &mut HashMap<String, String>,
+    ) -> Result<Option<(usize, usize)>> {
+        match (fields.remove("files"), fields.remove("checksums")) {
+            (Some(files), Some(checksums)) => Ok(Some((files.parse()?, checksums.parse()?))),
+            (None, None) if version >= 3 => Ok(None),
+            (None, _) => anyhow::bail!("header lacks the files field"),
+            (_, None) => anyhow::bail!("header lacks the checksums field"),
+        }
+    }
+
+    /// Parses the `# key: value` lines following the magic line, each with its line number
+    fn parse(version: &str, lines: &[(usize, String)]) -> Result<Self> {
+        let mut fields = HashMap::new();
+        for (line_num, line) in lines {
+            let Some((key, value)) = parse_field(line) else {
+                anyhow::bail!("line {line_num}: malformed header line {line}");
+            };
+            if fields.contains_key(&key) {
+                anyhow::bail!("line {line_num}: header field {key} given twice");
+            }
+            fields.insert(key, value);
+        }
+        Self::from_fields(version, fields)
+    }
+
+    /// Parses the object leading an analysis file in JSON Lines or binary
+    pub fn parse_json(object: serde_json::Map<String, serde_json::Value>) -> Result<Self> {
+        let mut fields = object
+            .into_iter()
+            .map(|(key, value)| match value {
+                serde_json::Value::String(value) => (key, value),
+                value => (key, value.to_string()),
+            })
+            .collect::<HashMap<_, _>>();
+        let version = fields.remove(VERSION_KEY).unwrap_or_default();
+        Self::from_fields(&version, fields)
+    }
+
+    /// Every field must be present, and unknown fields are refused, as they mean the file was
+    /// written by a version of the format this build does not know
+    fn from_fields(version: &str, mut fields: HashMap<String, String>) -> Result<Self> {
+        let version = version
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| anyhow::anyhow!("Invalid format version {}", version.trim()))?;
+        anyhow::ensure!(
+            version <= FORMAT_VERSION,
+            "Format version {version} is newer than the supported version {FORMAT_VERSION}, upgrade dedup"
+        );
+        anyhow::ensure!(version > 0, "Invalid format version 0");
+
+        let mut take = |key: &str| {
+            fields
+                .remove(key)
+                .ok_or_else(|| anyhow::anyhow!("header lacks the {key} field"))
+        };
+        let generator = take("generator")?;
+        // Version 1 predates per-file records
+        let layout = match version {
+            1 => Layout::Sizes,
+            _ => {
+                let layout = take("layout")?;
+                Layout::from_str(&layout, false)
+                    .map_err(|_| anyhow::anyhow!("Unknown layout {layout}"))?
+            }
+        };
+        let algo = HashAlgo::from_name(&take("hash-algo")?)?;
+        let seed = match take("hash-seed")?.as_str() {
+            "none" => None,
+            seed => match seed
+                .strip_prefix("0x")
+                .map(|hex| u64::from_str_radix(hex, 16))
+            {
+                Some(Ok(seed)) => Some(seed),
+                _ => anyhow::bail!("Invalid hash seed {seed}"),
+            },
+        };
+        let root = PathBuf::from(take("root")?);
+        let host = take("host")?;
+        let created = DateTime::parse_from_rfc3339(&take("created")?)
+            .map_err(|e| anyhow::anyhow!("Invalid creation time: {e}"))?
+            .to_utc();
+        let counts = Self::take_counts(version, &mut fields)?;
+        if let Some(key) = fields.keys().next() {
+            anyhow::bail!("Unknown header field {key}");
+        }
+
+        anyhow::ensure!(
+            seed == algo.seed(),
+            "Checksums were computed with {algo} seeded with {}, while this build uses {}",
+            fmt_seed(seed),
+            fmt_seed(algo.seed())
+        );
+
+        Ok(Self {
+            version,
+            generator,
+            layout,
+            algo,
+            seed,
+            root,
+            host,
+            created,
+            files: counts.map_or(0, |(files, _)| files),
+            checksums: counts.map_or(0, |(_, checksums)| checksums),
+            streamed: counts.is_none(),
+        })
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut object = serde_json::Map::new();
+        object.insert(VERSION_KEY.to_string(), self.version.into());
+        for (key, value) in self.fields() {
+            object.insert(key.to_string(), value.into());
+        }
+        if !self.streamed {
+            object.insert("files".to_string(), self.files.into());
+            object.insert("checksums".to_string(), self.checksums.into());
+        }
+        object.into()
+    }
+
+    /// Sets the counts of a streamed file from the `fields` following its records
+    fn complete(&mut self, input: &Path, mut fields: HashMap<String, String>) -> Result<()> {
+        let (Some(files), Some(checksums)) = (fields.remove("files"), fields.remove("checksums"))
+        else {
+            anyhow::bail!(
+                "{} ends without the counts following streamed records, it may be truncated",
+                input.display()
+            );
+        };
+        self.files = files.parse()?;
+        self.checksums = checksums.parse()?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{MAGIC} {}", self.version)?;
+        for (key, value) in self.fields() {
+            writeln!(f, "# {key}: {value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits a `# key: value` line
+fn parse_field(line: &str) -> Option<(String, String)> {
+    line.strip_prefix('#')
+        .and_then(|field| field.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+}
+
+fn fmt_seed(seed: Option<u64>) -> String {
+    match seed {
+        Some(seed) => format!("{seed:#x}"),
+        None => "none".to_string(),
+    }
+}
+
+/// Name of this host, as recorded in analysis files
+pub fn hostname() -> String {
+    gethostname::gethostname().to_string_lossy().into_owned()
+}
+
+/// One checksum read from or written to an analysis file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Record {
+    /// Unknown for checksum listings, which do not record sizes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<usize>,
+    /// Modification time of `path` when it was hashed, in per-file analysis files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<DateTime<Utc>>,
+    pub chksum: String,
+    /// File the checksum was computed from, when recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+}
+
+/// Compression of an analysis file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Zstd,
+    Gzip,
+}
+
+impl Compression {
+    /// Picks the compression of the analysis file at `path` from its extension
+    pub fn from_extension(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("zst" | "zstd") => Compression::Zstd,
+            Some("gz") => Compression::Gzip,
+            _ => Compression::None,
+        }
+    }
+
+    /// Tells the compression of a stream from its first bytes
+    fn from_magic(bytes: &[u8]) -> Self {
+        if bytes.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else if bytes.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else {
+            Compression::None
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::None => "uncompressed",
+            Compression::Zstd => "zstd",
+            Compression::Gzip => "gzip",
+        })
+    }
+}
+
+type Input = BufReader<Box<dyn AsyncRead + Unpin>>;
+
+/// Decompresses `reader` if its first bytes say so. Files named as compressed must be so
+async fn decompress(filepath: &Path, mut reader: Input) -> Result<(Input, Compression)> {
+    let compression = Compression::from_magic(reader.fill_buf().await?);
+    let named = Compression::from_extension(filepath);
+    if named != Compression::None && named != compression {
+        anyhow::bail!("{} is not {named} compressed", filepath.display());
+    }
+
+    let reader: Box<dyn AsyncRead + Unpin> = match compression {
+        Compression::None => return Ok((reader, compression)),
+        Compression::Zstd => {
+            let mut decoder = ZstdDecoder::new(reader);
+            decoder.multiple_members(true);
+            Box::new(decoder)
+        }
+        Compression::Gzip => {
+            let mut decoder = GzipDecoder::new(reader);
+            decoder.multiple_members(true);
+            Box::new(decoder)
+        }
+    };
+    debug!("{}: decompressing {compression}", filepath.display());
+    Ok((BufReader::new(reader), compression))
+}
+
+/// Analysis file being written to a hidden sibling, renamed over the file once complete so
+/// readers never see a partial file, or stdout for `-`
+struct Output {
+    writer: Box<dyn AsyncWrite + Unpin>,
+    path: PathBuf,
+    /// Sibling being written, until renamed
+    temp: Option<PathBuf>,
+}
+
+impl Output {
+    /// Compresses the output as the extension of `output_file` says
+    async fn create(output_file: &Path) -> Result<Self> {
+        if output_file == Path::new("-") {
+            return Ok(Self {
+                writer: Box::new(BufWriter::new(tokio::io::stdout())),
+                path: output_file.to_path_buf(),
+                temp: None,
+            });
+        }
+        let temp = temp_sibling(output_file)?;
+        let writer = BufWriter::new(temp.open_rw().await?);
+        Ok(Self {
+            writer: match Compression::from_extension(output_file) {
+                Compression::None => Box::new(writer),
+                Compression::Zstd => Box::new(ZstdEncoder::new(writer)),
+                Compression::Gzip => Box::new(GzipEncoder::new(writer)),
+            },
+            path: output_file.to_path_buf(),
+            temp: Some(temp),
+        })
+    }
+
+    async fn finish(mut self) -> Result<()> {
+        // Also finishes compressed streams
+        self.writer.shutdown().await?;
+        if let Some(temp) = self.temp.take() {
+            replace_with(&temp, &self.path).await?;
+        }
+        debug!("Analysis written to file {}", self.path.display());
+        Ok(())
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        if let Some(temp) = &self.temp {
+            let _ = std::fs::remove_file(temp);
+        }
+    }
+}
+
+/// Writes an analysis file holding `records` in `format`, sorted by size or by path
+pub async fn write_inventory<P: AsRef<Path>>(
+    header: &Header,
+    records: &mut [Record],
+    format: Format,
+    output_file: P,
+) -> Result<()> {
+    if format == Format::Binary {
+        anyhow::ensure!(
+            header.layout == Layout::Sizes,
+            "The binary format cannot hold {} records",
+            header.layout
+        );
+        let digests = records
+            .iter()
+            .map(|record| {
+                // Checksum listings name no sizes, and a made up one would never match
+                let Some(size) = record.size else {
+                    anyhow::bail!(
+                        "Record of {} lacks a size, which the binary format requires",
+                        record.chksum
+                    );
+                };
+                Ok((size as u64, header.algo.to_digest(&record.chksum)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let bytes = binary::encode(header, digests)?;
+        let mut output = Output::create(output_file.as_ref()).await?;
+        output.writer.write_all(&bytes).await?;
+        return output.finish().await;
+    }
+
+    match header.layout {
+        Layout::Sizes => {
+            records.sort_unstable_by(|a, b| (a.size, &a.chksum).cmp(&(b.size, &b.chksum)))
+        }
+        Layout::Files => records.sort_unstable_by(|a, b| a.path.cmp(&b.path)),
+    }
+    let mut writer = InventoryWriter::create(header, format, output_file.as_ref()).await?;
+    for group in records.chunk_by(|a, b| {
+        // Checksums of a size share a line of text
+        format == Format::Text && header.layout == Layout::Sizes && a.size == b.size
+    }) {
+        writer.write(group).await?;
+    }
+    writer.finish(header.files, header.checksums).await
+}
+
+/// Analysis file in any format but binary, written record by record after its header
+pub struct InventoryWriter {
+    output: Output,
+    format: Format,
+    layout: Layout,
+    streamed: bool,
+}
+
+impl InventoryWriter {
+    /// Creates `output_file`, or writes to stdout for `-`, opening it with `header`
+    pub async fn create(header: &Header, format: Format, output_file: &Path) -> Result<Self> {
+        let preamble = match format {
+            Format::Text => header.to_string(),
+            Format::Jsonl => format!("{}\n", header.to_json()),
+            Format::Csv => format!("{header}{}\n", CSV_COLUMNS.join(",")),
+            Format::Binary => anyhow::bail!("The binary format cannot be written record by record"),
+        };
+        let mut output = Output::create(output_file).await?;
+        output.writer.write_all(preamble.as_bytes()).await?;
+        Ok(Self {
+            output,
+            format,
+            layout: header.layout,
+            streamed: header.streamed,
+        })
+    }
+
+    /// Writes `records`, on a single line when they share a size in the text format and the
+    /// sizes layout, or on a line each
+    pub async fn write(&mut self, records: &[Record]) -> Result<()> {
+        let lines = match (self.format, self.layout) {
+            (Format::Text, Layout::Sizes) => sizes_line(records)?,
+            _ => records
+                .iter()
+                .map(|record| self.line(record))
+                .collect::<Result<String>>()?,
+        };
+        self.output.writer.write_all(lines.as_bytes()).await?;
+        Ok(())
+    }
+
+    fn line(&self, record: &Record) -> Result<String> {
+        Ok(match self.format {
+            Format::Text => {
+                let (Some(size), Some(mtime), Some(path)) =
+                    (record.size, record.mtime, &record.path)
+                else {
+                    anyhow::bail!("Incomplete record of {}", record.chksum);
+                };
+                let mtime = mtime.to_rfc3339_opts(chrono::SecondsFormat::Nanos, true);
+                format!("{size}\t{mtime}\t{}\t{}\n", record.chksum, escape(path))
+            }
+            Format::Jsonl => {
+                let line = serde_json::to_string(record).map_err(|e| {
+                    anyhow::anyhow!(
+                        "Cannot write {} in JSON, use the text format: {e}",
+                        describe(record)
+                    )
+                })?;
+                line + "\n"
+            }
+            Format::Csv => csv_row(record)?,
+            Format::Binary => unreachable!("refused on creation"),
+        })
+    }
+
+    /// Writes the counts following the records of streamed files, and finishes the output
+    pub async fn finish(mut self, files: usize, checksums: usize) -> Result<()> {
+        if self.streamed {
+            let trailer = match self.format {
+                Format::Jsonl => format!("{{\"files\":{files},\"checksums\":{checksums}}}\n"),
+                _ => format!("# files: {files}\n# checksums: {checksums}\n"),
+            };
+            self.output.writer.write_all(trailer.as_bytes()).await?;
+        }
+        self.output.finish().await
+    }
+}
+
+/// `size:chksum,chksum` line of `records`, which must share a size
+fn sizes_line(records: &[Record]) -> Result<String> {
+    let Some(first) = records.first() else {
+        return Ok(String::new());
+    };
+    let Some(size) = first.size else {
+        anyhow::bail!("Record of {} lacks a size", first.chksum);
+    };
+    anyhow::ensure!(
+        records.iter().all(|record| record.size == first.size),
+        "Records of a line differ in size"
+    );
+    let chksums = records
+        .iter()
+        .map(|record| record.chksum.as_str())
+        .collect::<Vec<_>>();
+    Ok(format!("{size}:{}\n", chksums.join(",")))
+}
+
+fn describe(record: &Record) -> String {
+    match &record.path {
+        Some(path) => path.display().to_string(),
+        None => record.chksum.clone(),
+    }
+}
+
+fn csv_row(record: &Record) -> Result<String> {
+    let path = match &record.path {
+        Some(path) => match path.to_str() {
+            Some(path) => path,
+            None => anyhow::bail!(
+                "Cannot write {} in CSV, use the text format: not UTF-8",
+                path.display()
+            ),
+        },
+        None => "",
+    };
+    let size = record.size.map(|size| size.to_string()).unwrap_or_default();
+    let mtime = record
+        .mtime
+        .map(|mtime| mtime.to_rfc3339_opts(chrono::SecondsFormat::Nanos, true))
+        .unwrap_or_default();
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record([size.as_str(), &mtime, &record.chksum, path])?;
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+/// Syntax of the lines of an input file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Syntax {
+    /// `size:chksum,chksum` lines, as written by analyze
+    Sizes,
+    /// `size<TAB>mtime<TAB>chksum<TAB>path` lines, as written by analyze with per-file records
+    Files,
+    /// JSON objects, one per line
+    Jsonl,
+    /// Comma separated values, following a row naming some of [`CSV_COLUMNS`]
+    Csv,
+    /// `chksum  name` or `chksum *name` lines, as written by md5sum, sha256sum or b3sum
+    Gnu,
+    /// `ALGO (name) = chksum` lines, as written by BSD tools and `--tag`
+    Bsd,
+}
+
+impl Syntax {
+    /// Tells the syntax of an input from its first line past the header, if it has one
+    fn detect(line: &str, header: Option<&Header>) -> Self {
+        if line.starts_with('{') {
+            return Syntax::Jsonl;
+        }
+        let mut columns = line.split(',').map(str::trim);
+        if columns.clone().any(|column| column == "chksum")
+            && columns.all(|column| CSV_COLUMNS.contains(&column))
+        {
+            return Syntax::Csv;
+        }
+        if header.is_some_and(|header| header.layout == Layout::Files) {
+            return Syntax::Files;
+        }
+
+        let line = line.strip_prefix('\\').unwrap_or(line);
+        if line
+            .split_once(':')
+            .is_some_and(|(size, _)| size.trim().parse::<usize>().is_ok())
+        {
+            Syntax::Sizes
+        } else if line.contains(" (") && line.contains(") = ") {
+            Syntax::Bsd
+        } else {
+            Syntax::Gnu
+        }
+    }
+}
+
+/// Checksums of a reference tree, as read from an analysis file or a checksum listing
+#[derive(Debug)]
+pub struct Inventory {
+    pub algo: HashAlgo,
+    /// Encoding of the input, checksum listings counting as text
+    pub format: Format,
+    /// Header of versioned analysis files
+    pub header: Option<Header>,
+    /// Checksums by size, each with the first file recorded to have it, if any
+    by_size: HashMap<usize, HashMap<String, Option<PathBuf>>>,
+    /// Checksums of unknown size, which match files of any size
+    sizeless: HashMap<String, Option<PathBuf>>,
+    len: usize,
+    /// Binary analysis file, searched in place of the maps above
+    packed: Option<Packed>,
+    /// Every record read, when asked to keep them
+    records: Vec<Record>,
+}
+
+/// State of an inventory being read
+struct Loader<'a> {
+    inventory: Inventory,
+    input: &'a Path,
+    requested: Option<HashAlgo>,
+    /// Algorithm of the checksums read so far, once known
+    settled_algo: Option<HashAlgo>,
+    num_records: usize,
+    /// Keeps every record in the inventory besides indexing it
+    keep_records: bool,
+}
+
+impl Loader<'_> {
+    /// Adds `records` found on `line_num`, computed with `algo` if the input names it
+    fn accept(
+        &mut self,
+        line_num: usize,
+        algo: Option<HashAlgo>,
+        syntax: Syntax,
+        records: Vec<Record>,
+    ) -> Result<()> {
+        let input = self.input.display();
+        // The first record settles the algorithm of the whole input
+        match (algo, self.settled_algo) {
+            (Some(algo), Some(settled)) if algo != settled => anyhow::bail!(
+                "{input}:{line_num}: {algo} checksum in a list of {settled} checksums"
+            ),
+            (_, Some(_)) => {}
+            (algo, None) => {
+                let Some(first) = records.first() else {
+                    return Ok(());
+                };
+                self.inventory.algo = match (algo, self.requested) {
+                    (Some(algo), _) => agree(self.input, algo, self.requested)?,
+                    (None, Some(requested)) => requested,
+                    (None, None) if syntax == Syntax::Sizes => HashAlgo::Xxh3_64,
+                    (None, None) => {
+                        let algo = guess_algo(&first.chksum, syntax)?;
+                        if algo == HashAlgo::Md5 {
+                            info!(
+                                "{input}: taking 32 hex digit checksums for md5, pass --hash-algo xxh3-128 if they are not"
+                            );
+                        }
+                        algo
+                    }
+                };
+                self.settled_algo = Some(self.inventory.algo);
+            }
+        }
+
+        self.num_records += records.len();
+        for mut record in records {
+            record.chksum = self
+                .inventory
+                .algo
+                .normalize(&record.chksum)
+                .map_err(|e| anyhow::anyhow!("{input}:{line_num}: {e}"))?;
+            if self.keep_records {
+                self.inventory.records.push(record.clone());
+            }
+            self.inventory.insert(record);
+        }
+        Ok(())
+    }
+}
+
+impl Inventory {
+    /// Reads `input_file`, or stdin for `-`, in any supported format. Checksum listings do not
+    /// name their algorithm unless BSD-style, so `requested` tells apart those of equal length.
+    /// Inputs naming an algorithm other than `requested` are refused
+    pub async fn read<P: AsRef<Path>>(input_file: P, requested: Option<HashAlgo>) -> Result<Self> {
+        Self::load(input_file.as_ref(), requested, false).await
+    }
+
+    /// Reads `input_file` like [`Self::read`], keeping every record it holds in the order read
+    pub async fn read_all<P: AsRef<Path>>(
+        input_file: P,
+        requested: Option<HashAlgo>,
+    ) -> Result<Self> {
+        Self::load(input_file.as_ref(), requested, true).await
+    }
+
+    async fn load(
+        filepath: &Path,
+        requested: Option<HashAlgo>,
+        keep_records: bool,
+    ) -> Result<Self> {
+        let reader: Box<dyn AsyncRead + Unpin> = match filepath.to_str() {
+            Some("-") => Box::new(tokio::io::stdin()),
+            Some(path) => Box::new(path.open_ro().await?),
+            None => anyhow::bail!("Invalid filename"),
+        };
+        let (mut reader, compression) = decompress(filepath, BufReader::new(reader)).await?;
+        if reader.fill_buf().await?.starts_with(binary::MAGIC) {
+            let mappable = filepath != Path::new("-") && compression == Compression::None;
+            return Self::read_binary(filepath, reader, mappable, requested, keep_records).await;
+        }
+
+        let mut loader = Loader {
+            inventory: Inventory {
+                algo: requested.unwrap_or_default(),
+                format: Format::Text,
+                header: None,
+                by_size: HashMap::new(),
+                sizeless: HashMap::new(),
+                len: 0,
+                packed: None,
+                records: Vec::new(),
+            },
+            input: filepath,
+            requested,
+            settled_algo: None,
+            num_records: 0,
+            keep_records,
+        };
+        let mut recorded_algo = None;
+        let mut syntax = None;
+        let mut lines = reader.lines();
+        let mut line_num = 0;
+        let mut pending = None;
+        // Counts following the records of streamed files
+        let mut trailer = HashMap::new();
+
+        // Versioned analysis files open with the magic line and a block of header lines, or
+        // with a header object in JSON Lines
+        if let Some(first) = lines.next_line().await? {
+            line_num += 1;
+            let header = if let Some(version) = first.strip_prefix(MAGIC) {
+                let mut header_lines = Vec::new();
+                while let Some(line) = lines.next_line().await? {
+                    line_num += 1;
+                    if !line.starts_with('#') {
+                        pending = Some(line);
+                        break;
+                    }
+                    header_lines.push((line_num, line));
+                }
+                Some(Header::parse(version, &header_lines))
+            } else if let Ok(serde_json::Value::Object(object)) = serde_json::from_str(&first)
+                && object.contains_key(VERSION_KEY)
+            {
+                syntax = Some(Syntax::Jsonl);
+                Some(Header::parse_json(object))
+            } else {
+                pending = Some(first);
+                None
+            };
+
+            if let Some(header) = header {
+                let header = header.map_err(|e| anyhow::anyhow!("{}: {e}", filepath.display()))?;
+                loader.inventory.algo = agree(filepath, header.algo, requested)?;
+                loader.settled_algo = Some(header.algo);
+                recorded_algo = Some(header.algo);
+                loader.inventory.header = Some(header);
+            }
+        }
+
+        loop {
+            let line = match pending.take() {
+                Some(line) => line,
+                None => match lines.next_line().await? {
+                    Some(line) => {
+                        line_num += 1;
+                        line
+                    }
+                    None => break,
+                },
+            };
+            if let Some(name) = line.strip_prefix(ALGO_HEADER) {
+                recorded_algo = Some(HashAlgo::from_name(name.trim())?);
+                continue;
+            }
+            if line.starts_with('#') {
+                trailer.extend(parse_field(&line));
+                continue;
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let syntax = *syntax
+                .get_or_insert_with(|| Syntax::detect(&line, loader.inventory.header.as_ref()));
+            if syntax == Syntax::Csv {
+                // Quoted fields may span lines, so the rest goes to the CSV reader in one piece
+                let mut rest = line.into_bytes();
+                rest.push(b'\n');
+                lines.into_inner().read_to_end(&mut rest).await?;
+                read_csv(&mut loader, line_num, recorded_algo, &rest)?;
+                // The CSV reader skips comments, and with them the lines ending the input
+                let comments = rest.rsplit(|&b| b == b'\n').filter(|line| !line.is_empty());
+                for line in comments.take_while(|line| line.starts_with(b"#")) {
+                    trailer.extend(parse_field(&String::from_utf8_lossy(line)));
+                }
+                break;
+            }
+            if syntax == Syntax::Jsonl
+                && !line.contains("\"chksum\"")
+                && let Ok(serde_json::Value::Object(object)) = serde_json::from_str(&line)
+            {
+                // Records name their checksum, the counts of streamed files follow them alone
+                trailer.extend(
+                    object
+                        .into_iter()
+                        .map(|(key, value)| (key, value.to_string())),
+                );
+                continue;
+            }
+
+            let (algo, records) = match syntax {
+                Syntax::Sizes => parse_sizes(&line).map(|records| (recorded_algo, records)),
+                Syntax::Files => parse_file(&line).map(|record| (recorded_algo, vec![record])),
+                Syntax::Jsonl => serde_json::from_str(&line)
+                    .map(|record| (recorded_algo, vec![record]))
+                    .map_err(Into::into),
+                Syntax::Gnu => parse_gnu(&line).map(|(algo, record)| (algo, vec![record])),
+                Syntax::Bsd => parse_bsd(&line).map(|(algo, record)| (algo, vec![record])),
+                Syntax::Csv => unreachable!("CSV is read in one piece"),
+            }
+            .map_err(|e| anyhow::anyhow!("{}:{line_num}: {e}", filepath.display()))?;
+            loader.accept(line_num, algo, syntax, records)?;
+        }
+
+        let num_records = loader.num_records;
+        let mut inventory = loader.inventory;
+        inventory.format = match syntax {
+            Some(Syntax::Jsonl) => Format::Jsonl,
+            Some(Syntax::Csv) => Format::Csv,
+            _ => Format::Text,
+        };
+        if let Some(header) = &mut inventory.header
+            && header.streamed
+        {
+            header.complete(filepath, trailer)?;
+        }
+        match &inventory.header {
+            Some(header) => anyhow::ensure!(
+                num_records == header.records(),
+                "{} holds {num_records} records while its header promises {}, it may be truncated",
+                filepath.display(),
+                header.records()
+            ),
+            None if syntax == Some(Syntax::Sizes) => warn!(
+                "{} predates versioned analysis files, so it cannot be checked for truncation",
+                filepath.display()
+            ),
+            None => {}
+        }
+        Ok(inventory)
+    }
+
+    /// Opens a binary analysis file in place when `mappable`, or reads it whole from `reader`
+    async fn read_binary(
+        filepath: &Path,
+        mut reader: Input,
+        mappable: bool,
+        requested: Option<HashAlgo>,
+        keep_records: bool,
+    ) -> Result<Self> {
+        let bytes = if !mappable {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+            Bytes::Owned(bytes)
+        } else {
+            let file = std::fs::File::open(filepath)?;
+            // SAFETY: the map is read only. Should the file be truncated while mapped, reads
+            // fault, as they would with any mapped file; every offset is bounds checked otherwise
+            Bytes::Mapped(unsafe { memmap2::Mmap::map(&file)? })
+        };
+        let in_file = |e| anyhow::anyhow!("{}: {e}", filepath.display());
+        let packed = Packed::open(bytes).map_err(in_file)?;
+        let algo = agree(filepath, packed.algo(), requested)?;
+        let records = match keep_records {
+            true => packed
+                .records()
+                .map_err(in_file)?
+                .into_iter()
+                .map(|(size, digest)| {
+                    Ok(Record {
+                        size: Some(size as usize),
+                        mtime: None,
+                        chksum: algo.from_digest(digest)?,
+                        path: None,
+                    })
+                })
+                .collect::<Result<_>>()?,
+            false => Vec::new(),
+        };
+        Ok(Self {
+            algo,
+            format: Format::Binary,
+            header: Some(packed.header.clone()),
+            by_size: HashMap::new(),
+            sizeless: HashMap::new(),
+            len: packed.len(),
+            packed: Some(packed),
+            records,
+        })
+    }
+
+    /// Indexes `record`, whose checksum is normalized
+    fn insert(&mut self, record: Record) {
+        let chksums = match record.size {
+            Some(size) => self.by_size.entry(size).or_default(),
+            None => &mut self.sizeless,
+        };
+        if let Entry::Vacant(entry) = chksums.entry(record.chksum) {
+            entry.insert(record.path);
+            self.len += 1;
+        }
+    }
+
+    /// Records kept by [`Self::read_all`]
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    pub fn into_records(self) -> Vec<Record> {
+        self.records
+    }
+
+    /// Looks up a file of `size` with `chksum`, returning the reference file it duplicates
+    /// when the inventory records one
+    pub fn get(&self, size: usize, chksum: &str) -> Option<Option<&Path>> {
+        if let Some(packed) = &self.packed {
+            let digest = self.algo.to_digest(chksum).ok()?;
+            return packed.contains(size as u64, &digest).then_some(None);
+        }
+        self.by_size
+            .get(&size)
+            .and_then(|chksums| chksums.get(chksum))
+            .or_else(|| self.sizeless.get(chksum))
+            .map(Option::as_deref)
+    }
+
+    /// Whether a file of `size` with `chksum` is part of the inventory
+    pub fn contains(&self, size: usize, chksum: &str) -> bool {
+        self.get(size, chksum).is_some()
+    }
+
+    /// Whether a file of any size with `chksum` is part of the inventory. Binary analysis files
+    /// are only searched by checksum alone when read with [`Self::read_all`]
+    pub fn contains_chksum(&self, chksum: &str) -> bool {
+        if self.packed.is_some() {
+            return self.records.iter().any(|record| record.chksum == chksum);
+        }
+        self.sizeless.contains_key(chksum)
+            || self
+                .by_size
+                .values()
+                .any(|chksums| chksums.contains_key(chksum))
+    }
+
+    /// Names a reference file recorded by the inventory by its host and absolute path, when known
+    pub fn locate(&self, path: &Path) -> String {
+        match &self.header {
+            Some(header) => format!("{}:{}", header.host, header.root.join(path).display()),
+            None => path.display().to_string(),
+        }
+    }
+
+    /// Number of distinct checksums
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Reads the CSV in `bytes`, whose first row on `line_num` names the columns
+fn read_csv(
+    loader: &mut Loader,
+    line_num: usize,
+    algo: Option<HashAlgo>,
+    bytes: &[u8],
+) -> Result<()> {
+    let mut reader = csv::ReaderBuilder::new()
+        .comment(Some(b'#'))
+        .from_reader(bytes);
+    let input = loader.input.display().to_string();
+    let at = |row: Option<&csv::Position>| line_num + row.map_or(1, |pos| pos.line() as usize) - 1;
+    let columns = reader.headers()?.clone();
+    let mut row = csv::StringRecord::new();
+    loop {
+        match reader.read_record(&mut row) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => anyhow::bail!("{input}:{}: {e}", at(e.position())),
+        }
+        let row_num = at(row.position());
+        let record = row
+            .deserialize::<Record>(Some(&columns))
+            .map_err(|e| anyhow::anyhow!("{input}:{row_num}: {e}"))?;
+        loader.accept(row_num, algo, Syntax::Csv, vec![record])?;
+    }
+    Ok(())
+}
+
+/// Checks that the `algo` named by `input` is the one `requested`, if any
+fn agree(input: &Path, algo: HashAlgo, requested: Option<HashAlgo>) -> Result<HashAlgo> {
+    match requested {
+        Some(requested) if requested != algo => anyhow::bail!(
+            "{} holds {algo} checksums, but {requested} was requested",
+            input.display()
+        ),
+        _ => Ok(algo),
+    }
+}
+
+/// Picks the algorithm of checksums that do not name it from their length. 32 hex digits are
+/// taken for md5, by far the likeliest source of such listings, over xxh3-128
+fn guess_algo(chksum: &str, syntax: Syntax) -> Result<HashAlgo> {
+    match chksum.len() {
+        // Only dedup writes seeded xxh3-64 checksums, and checksum listing tools know nothing of it
+        1..=16 if syntax != Syntax::Gnu => Ok(HashAlgo::Xxh3_64),
+        32 => Ok(HashAlgo::Md5),
+        64 => anyhow::bail!("Cannot tell sha256 from blake3 checksums, pass --hash-algo"),
+        len @ 1..=16 => {
+            anyhow::bail!(
+                "No checksum listing tool writes {len} hex digit checksums, pass --hash-algo"
+            )
+        }
+        len => anyhow::bail!("No known algorithm has {len} hex digit checksums"),
+    }
+}
+
+fn parse_sizes(line: &str) -> Result<Vec<Record>> {
+    let Some((size, hashes)) = line.split_once(':') else {
+        anyhow::bail!("Failed to parse input line {line}");
+    };
+    let size = size.trim().parse()?;
+    Ok(hashes
+        .split(',')
+        .map(|chksum| Record {
+            size: Some(size),
+            mtime: None,
+            chksum: chksum.trim().to_string(),
+            path: None,
+        })
+        .collect())
+}
+
+fn parse_file(line: &str) -> Result<Record> {
+    let mut fields = line.splitn(4, '\t');
+    let (Some(size), Some(mtime), Some(chksum), Some(path)) =
+        (fields.next(), fields.next(), fields.next(), fields.next())
+    else {
+        anyhow::bail!("Failed to parse input line {line}");
+    };
+    let mtime = DateTime::parse_from_rfc3339(mtime)
+        .map_err(|e| anyhow::anyhow!("Invalid modification time {mtime}: {e}"))?;
+    Ok(Record {
+        size: Some(size.parse()?),
+        mtime: Some(mtime.to_utc()),
+        chksum: chksum.to_string(),
+        path: Some(unescape(path, true)),
+    })
+}
+
+/// Parses `chksum  name` (text mode) or `chksum *name` (binary mode) lines. A leading backslash
+/// marks names with escaped backslashes and newlines
+fn parse_gnu(line: &str) -> Result<(Option<HashAlgo>, Record)> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let Some((chksum, name)) = line.split_once(' ') else {
+        anyhow::bail!("Failed to parse checksum line {line}");
+    };
+    let Some(name) = name.strip_prefix([' ', '*']) else {
+        anyhow::bail!("Failed to parse checksum line {line}");
+    };
+
+    let record = Record {
+        size: None,
+        mtime: None,
+        chksum: chksum.to_string(),
+        path: Some(unescape(name, escaped)),
+    };
+    Ok((None, record))
+}
+
+/// Parses `ALGO (name) = chksum` lines. A leading backslash marks escaped names
+fn parse_bsd(line: &str) -> Result<(Option<HashAlgo>, Record)> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let parsed = line.split_once(" (").and_then(|(tag, rest)| {
+        let (name, chksum) = rest.rsplit_once(") = ")?;
+        Some((tag, name, chksum))
+    });
+    let Some((tag, name, chksum)) = parsed else {
+        anyhow::bail!("Failed to parse checksum line {line}");
+    };
+
+    let algo = match tag {
+        "MD5" => HashAlgo::Md5,
+        "SHA256" => HashAlgo::Sha256,
+        "BLAKE3" => HashAlgo::Blake3,
+        tag => anyhow::bail!("Unsupported checksum algorithm {tag}"),
+    };
+    let record = Record {
+        size: None,
+        mtime: None,
+        chksum: chksum.to_string(),
+        path: Some(unescape(name, escaped)),
+    };
+    Ok((Some(algo), record))
+}
+
+/// Escapes backslashes, line breaks and tabs in `path`, and bytes that are not UTF-8, so it
+/// fits on one field of a line
+fn escape(path: &Path) -> String {
+    let mut escaped = String::new();
+    for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                '\n' => escaped.push_str("\\n"),
+                '\r' => escaped.push_str("\\r"),
+                '\t' => escaped.push_str("\\t"),
+                c => escaped.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            write!(escaped, "\\x{byte:02x}").unwrap();
+        }
+    }
+    escaped
+}
+
+/// Undoes the escaping of file names by [`escape`] and the coreutils checksum tools
+fn unescape(name: &str, escaped: bool) -> PathBuf {
+    if !escaped {
+        return PathBuf::from(name);
+    }
+
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut chars = name.bytes();
+    while let Some(byte) = chars.next() {
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        match chars.next() {
+            Some(b'n') => bytes.push(b'\n'),
+            Some(b'r') => bytes.push(b'\r'),
+            Some(b't') => bytes.push(b'\t'),
+            Some(b'x') => {
+                let hex = [chars.next(), chars.next()];
+                match hex.map(|digit| digit.and_then(|digit| (digit as char).to_digit(16))) {
+                    [Some(high), Some(low)] => bytes.push((high * 16 + low) as u8),
+                    _ => bytes.extend([b'\\', b'x'].into_iter().chain(hex.into_iter().flatten())),
+                }
+            }
+            Some(other) => bytes.push(other),
+            None => bytes.push(b'\\'),
+        }
+    }
+    PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    /// Header of an analysis of `/srv` holding `len` records, created on a whole second as
+    /// headers record it
+    fn header(layout: Layout, len: usize) -> Header {
+        let mut header = Header::new(layout, HashAlgo::Xxh3_64, PathBuf::from("/srv"), len, len);
+        header.created = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        header
+    }
+
+    /// Parses `text` as the header lines of an analysis file, magic line first
+    fn parse_header(text: &str) -> Result<Header> {
+        let mut lines = text.lines();
+        let version = lines.next().and_then(|line| line.strip_prefix(MAGIC));
+        let lines: Vec<_> = lines
+            .enumerate()
+            .map(|(i, line)| (i + 2, line.to_string()))
+            .collect();
+        Header::parse(version.expect("magic line"), &lines)
+    }
+
+    fn refusal(text: &str) -> String {
+        parse_header(text).unwrap_err().to_string()
+    }
+
+    /// `text` without its `key` line
+    fn without(text: &str, key: &str) -> String {
+        let field = format!("# {key}:");
+        text.lines()
+            .filter(|line| !line.starts_with(&field))
+            .map(|line| format!("{line}\n"))
+            .collect()
+    }
+
+    /// Records of three files, two of a size, named to need quoting or escaping, with unpadded
+    /// checksums as computed
+    fn records(layout: Layout) -> Vec<Record> {
+        let mtime = DateTime::from_timestamp(1_700_000_000, 123_456_789);
+        [
+            (4096, "123456789ABCDEF", "a.txt"),
+            (4096, "FEDCBA9876543210", "dir/b, \"c\".txt"),
+            (12, "FF", "dir/d\te\nf.txt"),
+        ]
+        .into_iter()
+        .map(|(size, chksum, path)| Record {
+            size: Some(size),
+            mtime: mtime.filter(|_| layout == Layout::Files),
+            chksum: chksum.to_string(),
+            path: Some(PathBuf::from(path)).filter(|_| layout == Layout::Files),
+        })
+        .collect()
+    }
+
+    /// Writes `records` to `name` in `dir` and reads them back
+    async fn round_trip(
+        dir: &Path,
+        name: &str,
+        layout: Layout,
+        format: Format,
+        mut records: Vec<Record>,
+    ) -> Result<Inventory> {
+        let path = dir.join(name);
+        let header = header(layout, records.len());
+        write_inventory(&header, &mut records, format, &path).await?;
+        let inventory = Inventory::read_all(&path, None).await?;
+        assert_eq!(inventory.header, Some(header));
+        assert_eq!(inventory.format, format);
+        Ok(inventory)
+    }
+
+    /// Checks that `inventory` holds `records` in any order
+    fn assert_holds(inventory: &Inventory, records: &[Record]) {
+        let held: HashSet<_> = inventory.records().iter().collect();
+        assert_eq!(held, records.iter().collect());
+        assert_eq!(inventory.records().len(), records.len());
+    }
+
+    fn listed(chksum: &str, path: &str) -> Record {
+        Record {
+            size: None,
+            mtime: None,
+            chksum: chksum.to_string(),
+            path: Some(PathBuf::from(path)),
+        }
+    }
+
+    #[test]
+    fn gnu_lines_in_text_and_binary_mode() {
+        let (algo, record) = parse_gnu("d41d8cd98f00b204e9800998ecf8427e  a b.txt").unwrap();
+        assert_eq!(algo, None);
+        assert_eq!(
+            record,
+            listed("d41d8cd98f00b204e9800998ecf8427e", "a b.txt")
+        );
+        let (_, record) = parse_gnu("0123 *bin/x").unwrap();
+        assert_eq!(record, listed("0123", "bin/x"));
+    }
+
+    #[test]
+    fn gnu_lines_with_escaped_names() {
+        let (_, record) = parse_gnu("\\0123  a\\nb\\\\c").unwrap();
+        assert_eq!(record, listed("0123", "a\nb\\c"));
+        // Without the leading backslash, names are taken as they are
+        let (_, record) = parse_gnu("0123  a\\nb").unwrap();
+        assert_eq!(record, listed("0123", "a\\nb"));
+    }
+
+    #[test]
+    fn malformed_gnu_lines_are_refused() {
+        assert!(parse_gnu("0123").is_err());
+        assert!(parse_gnu("0123 -name").is_err());
+    }
+
+    #[test]
+    fn bsd_lines_name_their_algorithm() {
+        let (algo, record) = parse_bsd("SHA256 (dir/a (1).txt) = 0123").unwrap();
+        assert_eq!(algo, Some(HashAlgo::Sha256));
+        assert_eq!(record, listed("0123", "dir/a (1).txt"));
+        let (algo, record) = parse_bsd("\\MD5 (a\\tb) = 0123").unwrap();
+        assert_eq!(algo, Some(HashAlgo::Md5));
+        assert_eq!(record, listed("0123", "a\tb"));
+    }
+
+    #[test]
+    fn malformed_bsd_lines_are_refused() {
+        let err = parse_bsd("SHA1 (a) = 0123").unwrap_err();
+        assert_eq!(err.to_string(), "Unsupported checksum algorithm SHA1");
+        assert!(parse_bsd("MD5 (a) 0123").is_err());
+    }
+
+    #[test]
+    fn sizes_lines_share_a_size_across_checksums() {
+        let records = parse_sizes("4096: ab, cd").unwrap();
+        let chksums: Vec<_> = records.iter().map(|r| r.chksum.as_str()).collect();
+        assert_eq!(chksums, ["ab", "cd"]);
+        assert!(
+            records
+                .iter()
+                .all(|r| r.size == Some(4096) && r.path.is_none())
+        );
+        assert!(parse_sizes("4096 ab").is_err());
+        assert!(parse_sizes("big: ab").is_err());
+    }
+
+    #[test]
+    fn algorithms_are_guessed_from_checksum_length() {
+        let guess = |len, syntax| guess_algo(&"0".repeat(len), syntax);
+        for syntax in [Syntax::Jsonl, Syntax::Csv] {
+            assert_eq!(guess(1, syntax).unwrap(), HashAlgo::Xxh3_64);
+            assert_eq!(guess(16, syntax).unwrap(), HashAlgo::Xxh3_64);
+        }
+        for syntax in [Syntax::Gnu, Syntax::Jsonl] {
+            assert_eq!(guess(32, syntax).unwrap(), HashAlgo::Md5);
+            assert!(guess(64, syntax).is_err());
+            assert!(guess(40, syntax).is_err());
+            assert!(guess(0, syntax).is_err());
+        }
+        // Listing tools write no xxh3-64 checksums
+        let err = guess(16, Syntax::Gnu).unwrap_err();
+        assert!(err.to_string().ends_with("pass --hash-algo"), "{err}");
+    }
+
+    #[test]
+    fn headers_round_trip_as_lines_and_json() {
+        for layout in [Layout::Sizes, Layout::Files] {
+            let header = header(layout, 3);
+            assert_eq!(parse_header(&header.to_string()).unwrap(), header);
+            let serde_json::Value::Object(object) = header.to_json() else {
+                panic!("header is not an object");
+            };
+            assert_eq!(Header::parse_json(object).unwrap(), header);
+        }
+    }
+
+    #[test]
+    fn headers_of_other_versions_are_refused() {
+        let text = header(Layout::Files, 3).to_string();
+        let version = |version: &str| {
+            text.replacen(
+                &format!("{MAGIC} {FORMAT_VERSION}"),
+                &format!("{MAGIC} {version}"),
+                1,
+            )
+        };
+        let newer = (FORMAT_VERSION + 1).to_string();
+        assert!(refusal(&version(&newer)).contains("upgrade dedup"));
+        assert_eq!(refusal(&version("0")), "Invalid format version 0");
+        assert_eq!(refusal(&version("x")), "Invalid format version x");
+        // Version 1 predates layouts, and holds only sizes
+        let header = parse_header(&without(&version("1"), "layout")).unwrap();
+        assert_eq!((header.version, header.layout), (1, Layout::Sizes));
+    }
+
+    #[test]
+    fn headers_with_unknown_missing_or_repeated_fields_are_refused() {
+        let text = header(Layout::Files, 3).to_string();
+        assert_eq!(
+            refusal(&format!("{text}# colour: blue\n")),
+            "Unknown header field colour"
+        );
+        assert_eq!(
+            refusal(&without(&text, "root")),
+            "header lacks the root field"
+        );
+        assert_eq!(
+            refusal(&without(&text, "checksums")),
+            "header lacks the checksums field"
+        );
+        let err = refusal(&format!("{text}# host: elsewhere\n"));
+        assert!(err.ends_with("header field host given twice"), "{err}");
+
+        let serde_json::Value::Object(mut object) = header(Layout::Files, 3).to_json() else {
+            panic!("header is not an object");
+        };
+        object.insert("colour".to_string(), "blue".into());
+        let err = Header::parse_json(object).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown header field colour");
+    }
+
+    #[test]
+    fn checksums_of_other_seeds_are_refused() {
+        let text = header(Layout::Sizes, 3).to_string();
+        let seed = fmt_seed(HashAlgo::Xxh3_64.seed());
+        let err = refusal(&text.replace(&format!("# hash-seed: {seed}"), "# hash-seed: 0x1"));
+        assert!(err.contains("seeded with 0x1"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn files_holding_fewer_records_than_promised_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("analysis.txt");
+        let mut records = vec![Record {
+            size: Some(4096),
+            mtime: None,
+            chksum: "0123456789ABCDEF".to_string(),
+            path: None,
+        }];
+        write_inventory(&header(Layout::Sizes, 2), &mut records, Format::Text, &path)
+            .await
+            .unwrap();
+        let err = Inventory::read(&path, None).await.unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("holds 1 records while its header promises 2, it may be truncated"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn records_round_trip_in_every_text_format() {
+        let dir = tempfile::tempdir().unwrap();
+        for layout in [Layout::Sizes, Layout::Files] {
+            for format in [Format::Text, Format::Jsonl, Format::Csv] {
+                let records = records(layout);
+                let name = format!("{layout}.{format:?}");
+                let inventory = round_trip(dir.path(), &name, layout, format, records.clone())
+                    .await
+                    .unwrap();
+                assert_holds(&inventory, &records);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn names_that_are_not_utf8_need_the_text_format() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut records = records(Layout::Files);
+        records[0].path = Some(PathBuf::from(std::ffi::OsStr::from_bytes(b"caf\xe9.txt")));
+        let inventory = round_trip(
+            dir.path(),
+            "analysis.txt",
+            Layout::Files,
+            Format::Text,
+            records.clone(),
+        )
+        .await
+        .unwrap();
+        assert_holds(&inventory, &records);
+        for format in [Format::Jsonl, Format::Csv] {
+            let err = round_trip(
+                dir.path(),
+                "analysis",
+                Layout::Files,
+                format,
+                records.clone(),
+            )
+            .await
+            .unwrap_err();
+            assert!(err.to_string().contains("use the text format"), "{err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn every_format_round_trips_compressed_or_not() {
+        let dir = tempfile::tempdir().unwrap();
+        for format in [Format::Text, Format::Jsonl, Format::Csv, Format::Binary] {
+            for extension in ["", ".zst", ".gz"] {
+                let name = format!("analysis.{format:?}{extension}");
+                let records = records(Layout::Sizes);
+                let inventory =
+                    round_trip(dir.path(), &name, Layout::Sizes, format, records.clone())
+                        .await
+                        .unwrap();
+                assert_holds(&inventory, &records);
+
+                let path = dir.path().join(&name);
+                let bytes = std::fs::read(&path).unwrap();
+                assert_eq!(
+                    Compression::from_magic(&bytes),
+                    Compression::from_extension(&path)
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn files_named_compressed_must_be_so() {
+        let dir = tempfile::tempdir().unwrap();
+        let gzip = dir.path().join("analysis.gz");
+        // zstd and uncompressed data named as gzip
+        for name in ["analysis.zst", "analysis.txt"] {
+            let path = dir.path().join(name);
+            let mut records = records(Layout::Sizes);
+            write_inventory(&header(Layout::Sizes, 3), &mut records, Format::Text, &path)
+                .await
+                .unwrap();
+            std::fs::rename(&path, &gzip).unwrap();
+            let err = Inventory::read(&gzip, None).await.unwrap_err();
+            assert!(err.to_string().ends_with("is not gzip compressed"), "{err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn streamed_files_end_with_their_counts() {
+        let dir = tempfile::tempdir().unwrap();
+        for format in [Format::Text, Format::Jsonl, Format::Csv] {
+            let path = dir.path().join(format!("analysis.{format:?}"));
+            let records = records(Layout::Files);
+            let mut header = header(Layout::Files, 0);
+            header.streamed = true;
+            let mut writer = InventoryWriter::create(&header, format, &path)
+                .await
+                .unwrap();
+            for record in &records {
+                writer.write(std::slice::from_ref(record)).await.unwrap();
+            }
+            writer.finish(records.len(), 3).await.unwrap();
+
+            let inventory = Inventory::read_all(&path, None).await.unwrap();
+            assert_holds(&inventory, &records);
+            let header = inventory.header.unwrap();
+            assert!(header.streamed);
+            assert_eq!((header.files, header.checksums), (records.len(), 3));
+
+            // Cut short before its counts
+            let text = std::fs::read_to_string(&path).unwrap();
+            let trailer = match format {
+                Format::Jsonl => text.trim_end().rfind('\n').unwrap() + 1,
+                _ => text.find("# files:").unwrap(),
+            };
+            std::fs::write(&path, &text[..trailer]).unwrap();
+            let err = Inventory::read(&path, None).await.unwrap_err();
+            assert!(
+                err.to_string().ends_with(
+                    "ends without the counts following streamed records, it may be truncated"
+                ),
+                "{err}"
+            );
+        }
+    }
+}
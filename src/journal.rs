@@ -0,0 +1,183 @@
+use crate::action::{Action, Duplicate, QUARANTINE_MANIFEST};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+/// One committed action, as appended to the journal
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    /// Identifies the invocation of dedup the action was part of
+    pub run: String,
+    pub time: DateTime<Utc>,
+    /// Action as spelled on the command line
+    pub action: String,
+    pub original: PathBuf,
+    pub survivor: Option<PathBuf>,
+    pub size: usize,
+    pub chksum: String,
+    /// Copy on another machine the original duplicated, when named by the input of remote mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    /// Where the original was moved to by `trash` or `quarantine`
+    pub moved_to: Option<PathBuf>,
+    /// Manifest of the quarantine directory listing the original
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<PathBuf>,
+}
+
+/// Append-only record of every action committed by dedup, so that runs can be undone
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    run: String,
+}
+
+impl Journal {
+    /// Opens the journal at `path` for a new run
+    pub fn new(path: PathBuf) -> Self {
+        let run = chrono::Local::now().format("%Y%m%dT%H%M%S%.3f").to_string();
+        Self { path, run }
+    }
+
+    /// `$XDG_STATE_HOME/dedup/journal.jsonl`
+    pub fn default_path() -> Result<PathBuf> {
+        let state_home = match std::env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()) {
+            Some(state_home) => PathBuf::from(state_home),
+            None => match std::env::var_os("HOME") {
+                Some(home) => PathBuf::from(home).join(".local/state"),
+                None => anyhow::bail!("Neither XDG_STATE_HOME nor HOME are set"),
+            },
+        };
+        Ok(state_home.join("dedup").join("journal.jsonl"))
+    }
+
+    pub fn run(&self) -> &str {
+        &self.run
+    }
+
+    /// Records `action` taken on `duplicate`, found at the absolute path `original`
+    pub async fn record(
+        &self,
+        action: &Action,
+        duplicate: &Duplicate<'_>,
+        original: PathBuf,
+        moved_to: Option<PathBuf>,
+    ) -> Result<()> {
+        let entry = Entry {
+            run: self.run.clone(),
+            time: Utc::now(),
+            action: action.to_string(),
+            original,
+            survivor: duplicate.survivor.map(std::path::absolute).transpose()?,
+            size: duplicate.size,
+            chksum: duplicate.chksum.to_string(),
+            reference: duplicate.reference.map(str::to_string),
+            moved_to,
+            manifest: match action {
+                Action::Quarantine(dir) => {
+                    Some(std::path::absolute(dir.join(QUARANTINE_MANIFEST))?)
+                }
+                _ => None,
+            },
+        };
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Appends of a single line are atomic, so concurrent runs cannot interleave entries
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+}
+
+/// Reads every entry of the journal at `path`, oldest first
+pub async fn read_entries<P: AsRef<Path>>(path: P) -> Result<Vec<Entry>> {
+    let file = match tokio::fs::File::open(path.as_ref()).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut lines = BufReader::new(file).lines();
+    let mut entries = Vec::new();
+    let mut line_num = 0;
+    while let Some(line) = lines.next_line().await? {
+        line_num += 1;
+        let entry = serde_json::from_str(&line)
+            .map_err(|e| anyhow::anyhow!("{}:{line_num}: {e}", path.as_ref().display()))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn entries_are_appended_and_read_back_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::new(dir.path().join("state/dedup/journal.jsonl"));
+        let original = dir.path().join("tree/a");
+        let duplicate = Duplicate {
+            path: &original,
+            root: &dir.path().join("tree"),
+            survivor: Some(Path::new("tree/b")),
+            reference: None,
+            size: 4,
+            chksum: "0123",
+        };
+        journal
+            .record(&Action::Hardlink, &duplicate, original.clone(), None)
+            .await
+            .unwrap();
+        let moved_to = dir.path().join("q/a");
+        journal
+            .record(
+                &Action::Quarantine(dir.path().join("q")),
+                &duplicate,
+                original.clone(),
+                Some(moved_to.clone()),
+            )
+            .await
+            .unwrap();
+
+        let entries = read_entries(&journal.path).await.unwrap();
+        let actions = entries
+            .iter()
+            .map(|e| e.action.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(actions, ["hardlink", "quarantine"]);
+        assert!(entries.iter().all(|e| e.run == journal.run()));
+        assert!(entries.iter().all(|e| e.original == original));
+        // Survivors are recorded absolute, so undo works from any directory
+        let survivor = std::path::absolute("tree/b").unwrap();
+        assert_eq!(entries[0].survivor.as_ref(), Some(&survivor));
+        assert_eq!(entries[0].moved_to, None);
+        assert_eq!(entries[1].moved_to, Some(moved_to));
+        assert_eq!(entries[0].manifest, None);
+        let manifest = dir.path().join("q").join(QUARANTINE_MANIFEST);
+        assert_eq!(entries[1].manifest, Some(manifest));
+        assert_eq!((entries[1].size, entries[1].chksum.as_str()), (4, "0123"));
+    }
+
+    #[tokio::test]
+    async fn missing_journals_are_empty_and_corrupt_ones_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.jsonl");
+        assert!(read_entries(&path).await.unwrap().is_empty());
+        std::fs::write(&path, "{}\n").unwrap();
+        let err = read_entries(&path).await.unwrap_err();
+        assert!(err.to_string().contains("journal.jsonl:1: "), "{err}");
+    }
+}
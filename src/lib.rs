@@ -1,7 +1,15 @@
+pub mod action;
 pub mod analyze;
-pub mod hasher;
+pub mod binary;
+pub mod cache;
 pub mod fs;
+pub mod group;
+pub mod hasher;
 pub mod inplace;
+pub mod inventories;
+pub mod inventory;
+pub mod journal;
 pub mod local;
 pub mod remote;
-pub mod size;
+pub mod trash;
+pub mod undo;
@@ -1,23 +1,24 @@
 use anyhow::Result;
-use clap::Args;
-use futures::{stream, StreamExt};
+use clap::{Args, ValueEnum};
+use futures::{StreamExt, stream};
 use log::{debug, error, trace, warn};
 use std::{
     collections::{HashMap, HashSet},
     path::PathBuf,
 };
 use tokio::fs::{canonicalize, metadata};
-use walkdir::WalkDir;
 
-use crate::hasher::HashFile;
-use crate::fs::{DirOps, FileOps};
+use crate::action::{Duplicate, Executor, Outcome, Summary};
+use crate::fs::{DirOps, Walker};
+use crate::group::KeepPolicy;
+use crate::hasher::{HashAlgo, cached_chksum};
 
 #[derive(Args, Debug)]
 #[command(arg_required_else_help = true)]
 /// Dedups files in one folder while referencing another folder
 pub struct Local {
     /// Path to use as a reference to filter duplicates in local
-    #[arg(short, long, conflicts_with = "input_file")]
+    #[arg(short, long, required = true)]
     pub reference_path: Option<PathBuf>,
 
     /// Local Path containing files that need to be checked for duplicates
@@ -26,58 +27,60 @@ pub struct Local {
 }
 
 impl Local {
-    pub async fn dedup(&self, commit: bool) -> Result<(usize, usize)> {
+    pub async fn dedup(&self, exec: &Executor, algo: HashAlgo, walker: &Walker) -> Result<Summary> {
         debug!(
             "Starting size mode dedup as {} using remote path {}",
             self.local_path.display(),
             self.reference_path.as_ref().unwrap().display()
         );
         let remote_path = self.reference_path.as_ref().unwrap();
+        let resolver = exec.resolver(vec![remote_path.clone(), self.local_path.clone()]);
         let mut file_map = HashMap::new();
-        let (mut num_processed, mut num_duplicates) = (0, 0);
+        let mut summary = Summary::default();
 
-        if canonicalize(&remote_path).await? == canonicalize(&self.local_path).await? {
+        // Nested trees would share files, which could then be acted on as their own duplicates
+        let reference = canonicalize(&remote_path).await?;
+        let local = canonicalize(&self.local_path).await?;
+        if reference.starts_with(&local) || local.starts_with(&reference) {
             anyhow::bail!(
-                "In-place deduplication not yet supported. {} and {} are the same path.",
+                "{} and {} overlap. Use in-place mode to dedup files within a single tree.",
                 remote_path.display(),
                 self.local_path.display()
             );
         }
+        if exec.keep() != KeepPolicy::FirstInPathOrder {
+            warn!(
+                "--keep {} may keep local copies and act on reference copies under {}",
+                exec.keep().to_possible_value().unwrap().get_name(),
+                remote_path.display()
+            );
+        }
 
-        let entries = remote_path.walkdir();
+        let entries = remote_path.walkdir(walker);
         let mut stream = stream::iter(entries)
-            .map(move |path| {
-                async move {
-                    metadata(&path).await
-                        .inspect_err(|e| warn!("Error reading file info {}: {e}", path.display()))
-                        .map(|md| (md.len(), path))
-                        .ok()
-                }
+            .map(move |path| async move {
+                metadata(&path)
+                    .await
+                    .inspect_err(|e| warn!("Error reading file info {}: {e}", path.display()))
+                    .map(|md| (md.len(), path))
+                    .ok()
             })
             .buffer_unordered(num_cpus::get() * 4);
 
-        while let Some(Some((size, path))) = stream.next().await {
-            file_map
-                .entry(size)
-                .or_insert(HashSet::new())
-                .insert(path);
+        while let Some(entry) = stream.next().await {
+            let Some((size, path)) = entry else { continue };
+            file_map.entry(size).or_insert(HashSet::new()).insert(path);
         }
 
-        for entry in WalkDir::new(&self.local_path) {
-            let local_file = match entry {
-                Ok(file) => file.into_path(),
+        for local_file in self.local_path.walkdir(walker) {
+            summary.processed += 1;
+            let size = match metadata(&local_file).await {
+                Ok(md) => md.len(),
                 Err(e) => {
-                    error!("Error while walking {}: {}", self.local_path.display(), e);
+                    warn!("Error reading file info {}: {e}", local_file.display());
                     continue;
                 }
             };
-
-            if local_file.is_dir() || local_file.is_symlink() {
-                continue;
-            }
-
-            num_processed += 1;
-            let size = metadata(&local_file).await?.len();
             if !file_map.contains_key(&size) {
                 continue;
             }
@@ -88,26 +91,191 @@ impl Local {
                     local_file.display(),
                     file_map[&size]
                 );
-                let local_chksum = local_file.chksum()?;
+                let local_chksum = match cached_chksum(local_file.clone(), algo).await {
+                    Ok(chksum) => chksum,
+                    Err(e) => {
+                        warn!("Error hashing file {}: {e}", local_file.display());
+                        continue;
+                    }
+                };
+                let mut matched = None;
                 for remote_file in &file_map[&size] {
-                    let remote_chksum = remote_file.chksum()?;
+                    let remote_chksum = match cached_chksum(remote_file.clone(), algo).await {
+                        Ok(chksum) => chksum,
+                        Err(e) => {
+                            warn!("Error hashing file {}: {e}", remote_file.display());
+                            continue;
+                        }
+                    };
                     if local_chksum == remote_chksum {
-                        let action = if commit { "removing" } else { "found" };
-                        debug!(
-                            "{action} duplicate files: local={} remote={}",
+                        matched = Some(remote_file.clone());
+                        break;
+                    }
+                }
+
+                let Some(remote_file) = matched else { continue };
+                let pair = vec![remote_file.clone(), local_file.clone()];
+                match resolver.split(pair.clone()).await {
+                    Ok(split) if split.len() > 1 => {
+                        error!(
+                            "Cannot {} {} and {} across filesystems, leaving them alone",
+                            exec.action(),
                             local_file.display(),
                             remote_file.display()
                         );
-                        if let Err(e) = local_file.remove_file(commit).await {
-                            error!("Error {action}ing file {}: {e}", local_file.display());
-                        }
-                        num_duplicates += 1;
-                        break;
+                        summary.record(Outcome::Failed);
+                        continue;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        error!(
+                            "Error comparing the filesystems of {}: {e}",
+                            local_file.display()
+                        );
+                        continue;
                     }
                 }
+                let (survivor, duplicates) = match resolver.resolve(pair).await {
+                    Ok(ok) => ok,
+                    Err(e) => {
+                        error!("Error picking survivor for {}: {e}", local_file.display());
+                        continue;
+                    }
+                };
+                let mut outcome = Outcome::Applied;
+                for duplicate in duplicates {
+                    debug!(
+                        "found duplicate file {}, keeping {}",
+                        duplicate.display(),
+                        survivor.display()
+                    );
+                    let root = match resolver.root_of(&duplicate) {
+                        Ok(root) => root,
+                        Err(e) => {
+                            error!("Error processing file {}: {e}", duplicate.display());
+                            continue;
+                        }
+                    };
+                    let duplicate = Duplicate {
+                        path: &duplicate,
+                        root,
+                        survivor: Some(&survivor),
+                        reference: None,
+                        size: size as usize,
+                        chksum: &local_chksum.1,
+                    };
+                    outcome = exec.apply(&duplicate).await.unwrap_or_else(|e| {
+                        error!("Error processing file {}: {e}", duplicate.path.display());
+                        Outcome::Failed
+                    });
+                    summary.record(outcome);
+                }
+
+                // A local survivor stands in for the reference copy it displaced
+                if survivor == local_file && outcome == Outcome::Applied {
+                    let references = file_map.get_mut(&size).unwrap();
+                    references.remove(&remote_file);
+                    references.insert(local_file);
+                }
             }
         }
 
-        Ok((num_processed, num_duplicates))
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::Action;
+
+    fn tree(files: &[&str]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for file in files {
+            let path = dir.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "same").unwrap();
+        }
+        dir
+    }
+
+    fn local(dir: &tempfile::TempDir, reference: &str, local: &str) -> Local {
+        Local {
+            reference_path: Some(dir.path().join(reference)),
+            local_path: dir.path().join(local),
+        }
+    }
+
+    async fn dedup(local: &Local, keep: KeepPolicy) -> Result<Summary> {
+        let exec = Executor::new(true, keep, Action::Delete, None, None);
+        local
+            .dedup(&exec, HashAlgo::default(), &Walker::default())
+            .await
+    }
+
+    #[tokio::test]
+    async fn nested_trees_are_refused() {
+        let dir = tree(&["n/sub/a", "n/x/a"]);
+        for (reference, local_path) in [("n", "n/sub"), ("n/sub", "n"), ("n", "n")] {
+            let err = dedup(&local(&dir, reference, local_path), KeepPolicy::default())
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("overlap"), "{err}");
+        }
+        assert!(dir.path().join("n/sub/a").exists() && dir.path().join("n/x/a").exists());
+    }
+
+    #[tokio::test]
+    async fn reference_copies_survive_by_default() {
+        let dir = tree(&["ref/x/a", "ref/y/a", "loc/a", "loc/b", "loc/c"]);
+        std::fs::write(dir.path().join("loc/c"), "other").unwrap();
+        let summary = dedup(&local(&dir, "ref", "loc"), KeepPolicy::default())
+            .await
+            .unwrap();
+        assert_eq!((summary.processed, summary.duplicates), (3, 2));
+        for kept in ["ref/x/a", "ref/y/a", "loc/c"] {
+            assert!(dir.path().join(kept).exists(), "{kept}");
+        }
+        assert!(!dir.path().join("loc/a").exists() && !dir.path().join("loc/b").exists());
+    }
+
+    #[tokio::test]
+    async fn pairs_across_filesystems_cannot_be_linked() {
+        use std::os::unix::fs::MetadataExt;
+        let dir = tree(&["loc/a"]);
+        let dev = |path: &std::path::Path| std::fs::metadata(path).unwrap().dev();
+        let Some(other) = tempfile::tempdir_in("/dev/shm")
+            .ok()
+            .filter(|other| dev(other.path()) != dev(dir.path()))
+        else {
+            eprintln!("no second filesystem at /dev/shm");
+            return;
+        };
+        std::fs::write(other.path().join("a"), "same").unwrap();
+
+        let local = Local {
+            reference_path: Some(other.path().to_path_buf()),
+            local_path: dir.path().join("loc"),
+        };
+        let exec = Executor::new(true, KeepPolicy::default(), Action::Hardlink, None, None);
+        let summary = local
+            .dedup(&exec, HashAlgo::default(), &Walker::default())
+            .await
+            .unwrap();
+        assert_eq!((summary.duplicates, summary.failed), (0, 1));
+        assert_eq!(
+            std::fs::metadata(dir.path().join("loc/a")).unwrap().nlink(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn other_policies_may_keep_local_copies() {
+        let dir = tree(&["z/a", "sub/a"]);
+        let summary = dedup(&local(&dir, "z", "sub"), KeepPolicy::Alphabetical)
+            .await
+            .unwrap();
+        assert_eq!(summary.duplicates, 1);
+        assert!(dir.path().join("sub/a").exists() && !dir.path().join("z/a").exists());
     }
 }
@@ -1,11 +1,19 @@
 use anyhow::Result;
 use clap::{Parser, Subcommand};
+use dedup::action::{Action, Executor};
 use dedup::analyze::Analyze;
+use dedup::cache::{Cache, HashCache};
+use dedup::fs::{WalkOpts, Walker};
+use dedup::group::KeepPolicy;
+use dedup::hasher::HashAlgo;
 use dedup::inplace::InPlace;
+use dedup::inventories::Inventories;
+use dedup::journal::Journal;
 use dedup::local::Local;
 use dedup::remote::Remote;
-use dedup::size;
-use log::{debug, error};
+use dedup::undo::Undo;
+use log::{error, warn};
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -15,6 +23,48 @@ pub struct DedupOpts {
     #[arg(short, long)]
     pub commit: bool,
 
+    /// Which copy of a group of duplicates survives. Unused by remote mode, where the surviving
+    /// copy lives elsewhere. In local mode, only the default never acts on reference copies
+    #[arg(short, long, value_enum, default_value_t)]
+    pub keep: KeepPolicy,
+
+    /// What to do with duplicates. Symlinks are absolute unless `symlink=relative` is given
+    #[arg(short, long, value_parser = Action::parser(), default_value_t)]
+    pub action: Action,
+
+    /// Moves duplicates under this directory, mirroring their absolute path, as in
+    /// `DIR/srv/photos/a.jpg`, and recording them in a manifest, instead of applying `--action`.
+    /// Never walked, even within a tree being deduped
+    #[arg(short, long, conflicts_with = "action")]
+    pub quarantine: Option<PathBuf>,
+
+    /// Compares duplicates byte for byte with their survivor before acting on them, and leaves
+    /// them alone if they differ. On by default with --commit, except in remote mode where the
+    /// survivors live elsewhere
+    #[arg(long, overrides_with = "no_verify")]
+    pub verify: bool,
+
+    /// Trusts checksums alone to act on duplicates
+    #[arg(long, overrides_with = "verify")]
+    pub no_verify: bool,
+
+    /// Hashing algorithm used for checksumming [default: xxh3-64, or the algorithm of the input
+    /// file in remote mode]
+    #[arg(short = 'H', long, value_enum)]
+    pub hash_algo: Option<HashAlgo>,
+
+    /// Hashes every file instead of reusing checksums cached by earlier runs
+    #[arg(long)]
+    pub no_cache: bool,
+
+    /// Journal recording committed actions so they can be undone
+    /// [default: $XDG_STATE_HOME/dedup/journal.jsonl]
+    #[arg(short, long)]
+    pub journal: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub walk: WalkOpts,
+
     /// Flag count for log verbosity (info(1), debug(2), trace(3)) [default: warn(0)]
     #[arg(short, long, action = clap::ArgAction::Count)]
     pub verbosity: u8,
@@ -30,6 +80,9 @@ pub enum OperatingMode {
     Remote(Remote),
     Local(Local),
     InPlace(InPlace),
+    Undo(Undo),
+    Cache(Cache),
+    Inventory(Inventories),
 }
 fn init_logging(verbosity: u8) -> Result<()> {
     let log_level = match verbosity {
@@ -46,22 +99,84 @@ fn init_logging(verbosity: u8) -> Result<()> {
     Ok(())
 }
 
+/// Persists the checksums computed by this run, if caching them
+fn save_cache() {
+    if let Some(cache) = HashCache::installed()
+        && let Err(e) = cache.save()
+    {
+        warn!("Saving hash cache failed. Error: {e}");
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli_args = DedupOpts::parse();
-    let (mut num_processed, mut num_duplicates) = (0, 0);
-
     init_logging(cli_args.verbosity)?;
+    let action = match cli_args.quarantine {
+        Some(dir) => Action::Quarantine(dir),
+        None => cli_args.action,
+    };
+    // Resolved only when needed, as finding the default journal may fail
+    let journal_path = || {
+        cli_args
+            .journal
+            .clone()
+            .map_or_else(Journal::default_path, Ok)
+    };
+    let algo = cli_args.hash_algo.unwrap_or_default();
+    let hashing = !matches!(
+        cli_args.mode,
+        OperatingMode::Undo(_) | OperatingMode::Cache(_) | OperatingMode::Inventory(_)
+    );
+    // Modes that walk no tree need not look up the mount points given to them
+    let mut walker = match hashing {
+        true => cli_args.walk.walker()?,
+        false => Walker::default(),
+    };
+    // Quarantined files would be found again, and quarantined anew, under a tree being deduped
+    if hashing && let Action::Quarantine(dir) = &action {
+        if cli_args.commit {
+            std::fs::create_dir_all(dir)?;
+        }
+        if dir.exists() {
+            walker.skip_dir(dir)?;
+        }
+    }
+    // The cache only spares rehashing files, so runs go on without it
+    if hashing && !cli_args.no_cache {
+        match HashCache::default_path().and_then(HashCache::load) {
+            Ok(cache) => cache.install()?,
+            Err(e) => warn!("Hash cache unavailable, hashing every file. Error: {e}"),
+        }
+    }
 
-    match cli_args.mode {
+    let verify = match (cli_args.verify, cli_args.no_verify) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    };
+    let acting = matches!(
+        cli_args.mode,
+        OperatingMode::Remote(_) | OperatingMode::Local(_) | OperatingMode::InPlace(_)
+    );
+    let journal = match acting && cli_args.commit {
+        true => Some(Journal::new(journal_path()?)),
+        false => None,
+    };
+    let exec = Executor::new(cli_args.commit, cli_args.keep, action, verify, journal);
+
+    let summary = match cli_args.mode {
         OperatingMode::Analyze(args) => {
-            match args.analyze().await {
-                Ok(()) => return Ok(()),
+            match args.analyze(cli_args.hash_algo, &walker).await {
+                Ok(()) => {
+                    save_cache();
+                    return Ok(());
+                }
                 Err(e) => {
                     error!(
                         "Digest mode analysis failed at {} and writing out to {}. Error: {e}",
                         args.local_path.display(),
-                        args.output_file.display()
+                        args.update.as_ref().unwrap_or(&args.output_file).display()
                     );
                     std::process::exit(1);
                 }
@@ -69,7 +184,12 @@ async fn main() -> Result<()> {
         }
 
         OperatingMode::Remote(args) => {
-            (num_processed, num_duplicates) = match args.dedup(cli_args.commit).await {
+            if cli_args.verify {
+                warn!(
+                    "Duplicates cannot be verified in remote mode, as their survivors live elsewhere"
+                );
+            }
+            match args.dedup(&exec, cli_args.hash_algo, &walker).await {
                 Ok(ok) => ok,
                 Err(e) => {
                     error!(
@@ -82,44 +202,117 @@ async fn main() -> Result<()> {
             }
         }
 
-        OperatingMode::Local(args) => {
-            debug!(
-                "Starting size mode dedup as {} using remote path {}",
-                &args.local_path.display(),
-                args.reference_path.as_ref().unwrap().display()
-            );
-            (num_processed, num_duplicates) = match size::size_mode(
-                args.local_path.clone(),
-                args.reference_path.as_ref().unwrap().clone(),
-                cli_args.commit,
-            )
-            .await
-            {
-                Ok(ok) => ok,
+        OperatingMode::Local(args) => match args.dedup(&exec, algo, &walker).await {
+            Ok(ok) => ok,
+            Err(e) => {
+                error!(
+                    "Size mode dedup failed at {} using remote path {}. Error: {e}",
+                    &args.local_path.display(),
+                    args.reference_path.unwrap().display()
+                );
+                std::process::exit(1);
+            }
+        },
+
+        OperatingMode::InPlace(args) => match args.dedup(&exec, algo, &walker).await {
+            Ok(ok) => ok,
+            Err(e) => {
+                error!("In-place dedup failed at {:?}. Error: {e}", args.local_path);
+                std::process::exit(1);
+            }
+        },
+
+        OperatingMode::Undo(args) if args.list => {
+            let journal_path = journal_path()?;
+            if let Err(e) = args.list(&journal_path).await {
+                error!(
+                    "Listing runs in {} failed. Error: {e}",
+                    journal_path.display()
+                );
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+
+        OperatingMode::Undo(args) => {
+            let journal_path = journal_path()?;
+            match args.undo(&journal_path, cli_args.commit).await {
+                Ok((run, num_actions, num_reverted)) => {
+                    println!(
+                        "{num_reverted} of {num_actions} actions of run {run} {}",
+                        if cli_args.commit {
+                            "undone"
+                        } else {
+                            "can be undone"
+                        }
+                    );
+                    return Ok(());
+                }
                 Err(e) => {
                     error!(
-                        "Size mode dedup failed at {} using remote path {}. Error: {e}",
-                        &args.local_path.display(),
-                        args.reference_path.unwrap().display()
+                        "Undo failed using journal {}. Error: {e}",
+                        journal_path.display()
                     );
                     std::process::exit(1);
                 }
             }
         }
 
-        OperatingMode::InPlace(_args) => {}
-    }
+        OperatingMode::Cache(args) => {
+            let cache_path = HashCache::default_path()?;
+            if let Err(e) = args.run(&cache_path) {
+                error!("Managing cache {} failed. Error: {e}", cache_path.display());
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+
+        OperatingMode::Inventory(args) => {
+            if let Err(e) = args.run(cli_args.hash_algo).await {
+                error!(
+                    "Combining analysis files into {} failed. Error: {e}",
+                    args.output_file.display()
+                );
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+    };
+    save_cache();
 
     println!(
         "{} files processed. {} Duplicates {}",
-        num_processed,
-        num_duplicates,
+        summary.processed,
+        summary.duplicates,
         if cli_args.commit {
-            "deleted".to_string()
+            exec.action().past_tense()
         } else {
-            "found".to_string()
+            "found"
         }
     );
+    if summary.mismatched > 0 {
+        println!(
+            "{} files matched a checksum but not the contents of their survivor, and were left alone",
+            summary.mismatched
+        );
+    }
+    if summary.failed > 0 {
+        println!(
+            "{} duplicates could not be {}, and were left alone",
+            summary.failed,
+            if cli_args.commit {
+                exec.action().past_tense()
+            } else {
+                "processed"
+            }
+        );
+    }
+    if summary.linked > 0 {
+        println!(
+            "{} files were reached through symlinks, and were left alone",
+            summary.linked
+        );
+    }
 
     Ok(())
 }
@@ -1,20 +1,22 @@
-use crate::{hasher::HashFile, fs::{DirOps, FileOps}};
+use crate::{
+    action::{Duplicate, Executor, Outcome, Summary},
+    fs::{DirOps, Walker},
+    hasher::{HashAlgo, cached_chksum},
+    inventory::{Inventory, hostname},
+};
 use anyhow::Result;
 use clap::Args;
 use futures::{StreamExt, stream};
-use log::{debug, error, info};
-use std::{
-    collections::{HashMap, HashSet},
-    path::{Path, PathBuf},
-    sync::Arc,
-};
-use tokio::io::{AsyncBufReadExt, BufReader};
+use log::{debug, error, info, warn};
+use std::{path::PathBuf, sync::Arc};
+use tokio::fs::canonicalize;
 
 #[derive(Args, Debug)]
 #[command(arg_required_else_help = true)]
 /// Uses analysis from a file to dedup files
 pub struct Remote {
-    /// File containing hash analysis used to dedup files in `local_path`
+    /// File containing hash analysis used to dedup files in `local_path`, or a checksum listing
+    /// produced by md5sum, sha256sum or b3sum
     #[arg(short, long)]
     pub input_file: Option<PathBuf>,
 
@@ -24,7 +26,14 @@ pub struct Remote {
 }
 
 impl Remote {
-    pub async fn dedup(&self, commit: bool) -> Result<(usize, usize)> {
+    /// Hashes local files with the algorithm the input file was produced with, which `algo`
+    /// must agree with when given, and tells apart when the input does not name it
+    pub async fn dedup(
+        &self,
+        exec: &Executor,
+        algo: Option<HashAlgo>,
+        walker: &Walker,
+    ) -> Result<Summary> {
         debug!(
             "Starting remote mode dedup at {} using input file {}",
             self.local_path.display(),
@@ -35,79 +44,97 @@ impl Remote {
             anyhow::bail!("Local path not found - {}", self.local_path.display());
         }
 
-        let entries = self.local_path.walkdir();
-
-        let mut stream = stream::iter(entries)
-            .map(move |file_path| {
-                async move {
-                    debug!("Start analyzing file: {}", file_path.display());
-                    let file_path_clone = file_path.clone();
-                    async {
-                        let (size, chksum) = tokio::task::spawn_blocking(move || {
-                            file_path_clone.chksum()
-                        }).await??;
-                        debug!("Finished analyzing file: {}", file_path.display());
-                        Ok::<_, Box<dyn std::error::Error>>((size, chksum, file_path))
-                    }
-                    .await
-                    .ok()
-                }
-            })
-            .buffer_unordered(num_cpus::get() * 2);
+        if exec.action().needs_survivor() {
+            anyhow::bail!(
+                "Action {} needs a surviving copy on this machine, which remote mode lacks",
+                exec.action()
+            );
+        }
 
-        let (analysis, num_entries) = parse_input(&self.input_file.as_ref().unwrap()).await?;
+        let analysis = Inventory::read(self.input_file.as_ref().unwrap(), algo).await?;
+        let input_algo = analysis.algo;
+        if let Some(header) = &analysis.header {
+            info!(
+                "Input file describes {} files under {} on {}, analyzed at {} by {}",
+                header.files,
+                header.root.display(),
+                header.host,
+                header.created.with_timezone(&chrono::Local).format("%F %T"),
+                header.generator
+            );
+            // Every file of the reference tree is its own duplicate
+            let local_path = canonicalize(&self.local_path).await?;
+            if header.host == hostname() && local_path.starts_with(&header.root) {
+                anyhow::bail!(
+                    "{} lies within {}, the tree the input file was made from",
+                    local_path.display(),
+                    header.root.display()
+                );
+            }
+        }
         let analysis = Arc::new(analysis);
         info!(
-            "Found {} entries in input file {}",
-            num_entries,
+            "Found {} {input_algo} entries in input file {}",
+            analysis.len(),
             self.input_file.as_ref().unwrap().display()
         );
 
-        let (mut num_processed, mut num_duplicates) = (0, 0); //entries.size_hint().0;
-        while let Some(Some((size, chksum, file_path))) = stream.next().await {
-            num_processed += 1;
-            if let Some(chksums) = analysis.get(&size)
-                && chksums.contains(&chksum)
-            {
-                num_duplicates += 1;
-                let action = if commit { "remov" } else { "process" };
-                if let Err(e) = file_path.remove_file(commit).await {
-                    error!("error {action}ing file {}: {e}", file_path.display());
-                } else {
-                    debug!("successfully {action}ed file {}", file_path.display());
+        let entries = self.local_path.walkdir(walker);
+
+        let mut stream = stream::iter(entries)
+            .map(move |file_path| async move {
+                debug!("Start analyzing file: {}", file_path.display());
+                let file_path_clone = file_path.clone();
+                async {
+                    let (size, chksum) = cached_chksum(file_path_clone, input_algo).await?;
+                    debug!("Finished analyzing file: {}", file_path.display());
+                    Ok::<_, anyhow::Error>((size, chksum))
+                }
+                .await
+                .inspect_err(|e| warn!("Error hashing file {}: {e}", file_path.display()))
+                .map(|(size, chksum)| (size, chksum, file_path))
+                .ok()
+            })
+            .buffer_unordered(num_cpus::get() * 2);
+
+        let mut summary = Summary::default();
+        while let Some(entry) = stream.next().await {
+            let Some((size, chksum, file_path)) = entry else {
+                continue;
+            };
+            summary.processed += 1;
+            if let Some(reference) = analysis.get(size, &chksum) {
+                let reference = reference.map(|reference| analysis.locate(reference));
+                match &reference {
+                    Some(reference) => {
+                        info!("{} duplicates reference {reference}", file_path.display())
+                    }
+                    None => debug!("{} duplicates a reference file", file_path.display()),
+                }
+                let action = if exec.commit() { "remov" } else { "process" };
+                let duplicate = Duplicate {
+                    path: &file_path,
+                    root: &self.local_path,
+                    survivor: None,
+                    reference: reference.as_deref(),
+                    size,
+                    chksum: &chksum,
+                };
+                match exec.apply(&duplicate).await {
+                    Ok(outcome) => {
+                        summary.record(outcome);
+                        debug!("successfully {action}ed file {}", file_path.display());
+                    }
+                    Err(e) => {
+                        summary.record(Outcome::Failed);
+                        error!("error {action}ing file {}: {e}", file_path.display());
+                    }
                 }
             } else {
                 debug!("skipping file: {}", file_path.display());
             }
         }
 
-        Ok((num_processed, num_duplicates))
-    }
-}
-
-async fn parse_input<P: AsRef<Path>>(
-    input_file: P,
-) -> Result<(HashMap<usize, HashSet<String>>, usize)> {
-    let filepath = input_file.as_ref();
-    let mut entry_count = 0;
-    let reader: Box<dyn tokio::io::AsyncRead + Unpin> = match filepath.to_str() {
-        Some("-") => Box::new(tokio::io::stdin()),
-        Some(path) => Box::new(path.open_ro().await?),
-        None => anyhow::bail!("Invalid filename"),
-    };
-
-    let mut lines = BufReader::new(reader).lines();
-    let mut ret = HashMap::new();
-    while let Some(line) = lines.next_line().await? {
-        let Some((size, hashes)) = line.split_once(':') else {
-            anyhow::bail!("Failed to parse input line {line}");
-        };
-        let hashes = hashes
-            .split(',')
-            .map(|s| s.trim().to_string())
-            .collect::<HashSet<String>>();
-        entry_count += hashes.len();
-        ret.insert(size.trim().parse()?, hashes);
+        Ok(summary)
     }
-    Ok((ret, entry_count))
 }
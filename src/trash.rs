@@ -0,0 +1,179 @@
+use crate::fs::FileOps;
+use anyhow::Result;
+use log::trace;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::fs::{OpenOptions, canonicalize, metadata, symlink_metadata};
+use tokio::io::AsyncWriteExt;
+
+/// Moves `path` to the trash can of its filesystem, as laid out by the freedesktop.org Trash
+/// specification, and returns where it now lives
+pub async fn trash(path: &Path) -> Result<PathBuf> {
+    let path = canonicalize(path).await?;
+    let (trash_dir, topdir) = trash_dir_for(&path).await?;
+    for sub in ["files", "info"] {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(trash_dir.join(sub))?;
+    }
+
+    // The home trash records absolute paths, the others paths relative to their mount point
+    let recorded = match &topdir {
+        Some(topdir) => path.strip_prefix(topdir)?,
+        None => path.as_path(),
+    };
+    let contents = format!(
+        "[Trash Info]\nPath={}\nDeletionDate={}\n",
+        percent_encode(recorded),
+        chrono::Local::now().format("%Y-%m-%dT%H:%M:%S")
+    );
+
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("{}: not a file", path.display()))?;
+    let (info, dest) = reserve_name(&trash_dir, name, contents.as_bytes()).await?;
+    trace!("{}: trashing to {}", path.display(), dest.display());
+    if let Err(e) = path.move_to(&dest).await {
+        let _ = tokio::fs::remove_file(&info).await;
+        return Err(e);
+    }
+    Ok(dest)
+}
+
+/// Drops the `.trashinfo` file of a file that was trashed at `dest`, once restored from there
+pub async fn forget(dest: &Path) -> Result<()> {
+    let (Some(name), Some(trash_dir)) = (dest.file_name(), dest.parent().and_then(Path::parent))
+    else {
+        anyhow::bail!("{}: not in a trash can", dest.display());
+    };
+    let mut info_name = name.to_os_string();
+    info_name.push(".trashinfo");
+    Ok(tokio::fs::remove_file(trash_dir.join("info").join(info_name)).await?)
+}
+
+/// Picks the trash can for `path`, along with the mount point its paths are relative to
+async fn trash_dir_for(path: &Path) -> Result<(PathBuf, Option<PathBuf>)> {
+    let dev = metadata(path).await?.dev();
+    let home_trash = match std::env::var_os("XDG_DATA_HOME").filter(|dir| !dir.is_empty()) {
+        Some(data_home) => PathBuf::from(data_home).join("Trash"),
+        None => match std::env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".local/share/Trash"),
+            None => anyhow::bail!("Neither XDG_DATA_HOME nor HOME are set"),
+        },
+    };
+    if nearest_dev(&home_trash).await? == dev {
+        return Ok((home_trash, None));
+    }
+
+    let topdir = mount_point(path, dev).await?;
+    // SAFETY: getuid has no preconditions and cannot fail
+    let uid = unsafe { libc::getuid() };
+
+    // An admin-provided $topdir/.Trash must be a real, sticky directory to be trusted
+    let shared = topdir.join(".Trash");
+    if let Ok(md) = symlink_metadata(&shared).await
+        && md.is_dir()
+        && md.permissions().mode() & libc::S_ISVTX != 0
+    {
+        return Ok((shared.join(uid.to_string()), Some(topdir)));
+    }
+    Ok((topdir.join(format!(".Trash-{uid}")), Some(topdir)))
+}
+
+/// Device of `path`, or of its closest existing ancestor
+async fn nearest_dev(path: &Path) -> Result<u64> {
+    for ancestor in path.ancestors() {
+        if let Ok(md) = metadata(ancestor).await {
+            return Ok(md.dev());
+        }
+    }
+    anyhow::bail!("{}: no existing ancestor", path.display())
+}
+
+/// Topmost ancestor of `path` still on device `dev`
+async fn mount_point(path: &Path, dev: u64) -> Result<PathBuf> {
+    let mut topdir = path;
+    for ancestor in path.ancestors().skip(1) {
+        if metadata(ancestor).await?.dev() != dev {
+            break;
+        }
+        topdir = ancestor;
+    }
+    Ok(topdir.to_path_buf())
+}
+
+/// Claims a free name in `trash_dir` by exclusively creating its `.trashinfo` file, and returns
+/// the info file and the destination of the trashed file
+async fn reserve_name(
+    trash_dir: &Path,
+    name: &std::ffi::OsStr,
+    contents: &[u8],
+) -> Result<(PathBuf, PathBuf)> {
+    for attempt in 1.. {
+        let mut candidate = name.to_os_string();
+        if attempt > 1 {
+            candidate.push(format!(".{attempt}"));
+        }
+        let dest = trash_dir.join("files").join(&candidate);
+        let mut info_name = OsString::from(&candidate);
+        info_name.push(".trashinfo");
+        let info = trash_dir.join("info").join(info_name);
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&info)
+            .await;
+        let mut file = match file {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if symlink_metadata(&dest).await.is_ok() {
+            // Left behind by a tool that did not write its info file. Keep looking
+            drop(file);
+            tokio::fs::remove_file(&info).await?;
+            continue;
+        }
+        file.write_all(contents).await?;
+        file.flush().await?;
+        return Ok((info, dest));
+    }
+    unreachable!()
+}
+
+/// Escapes `path` the way URIs are, as the `Path` key of `.trashinfo` files requires
+fn percent_encode(path: &Path) -> String {
+    let mut encoded = String::new();
+    for &byte in path.as_os_str().as_bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_are_percent_encoded_byte_by_byte() {
+        let encode = |path: &str| percent_encode(Path::new(path));
+        assert_eq!(encode("/home/me/a-b_c.~1"), "/home/me/a-b_c.~1");
+        assert_eq!(encode("/tmp/a b%c"), "/tmp/a%20b%25c");
+        assert_eq!(encode("/tmp/é"), "/tmp/%C3%A9");
+    }
+
+    #[test]
+    fn paths_that_are_not_utf8_are_encoded_too() {
+        let path = Path::new(std::ffi::OsStr::from_bytes(b"/tmp/\xff"));
+        assert_eq!(percent_encode(path), "/tmp/%FF");
+    }
+}
@@ -0,0 +1,347 @@
+use crate::action;
+use crate::fs::FileOps;
+use crate::journal::{self, Entry};
+use crate::trash;
+use anyhow::Result;
+use clap::Args;
+use log::{debug, error, info, warn};
+use std::path::Path;
+use tokio::fs::symlink_metadata;
+
+#[derive(Args, Debug)]
+/// Reverts the actions committed by a previous run, where possible
+pub struct Undo {
+    /// Run to undo, as shown by `--list` [default: latest run]
+    pub run: Option<String>,
+
+    /// Lists the runs recorded in the journal instead of undoing one
+    #[arg(short, long)]
+    pub list: bool,
+}
+
+impl Undo {
+    /// Returns the run undone, how many actions it had and how many of them were reverted
+    pub async fn undo<P: AsRef<Path>>(
+        &self,
+        journal_path: P,
+        commit: bool,
+    ) -> Result<(String, usize, usize)> {
+        let entries = journal::read_entries(&journal_path).await?;
+        let Some(run) = self
+            .run
+            .clone()
+            .or_else(|| entries.last().map(|entry| entry.run.clone()))
+        else {
+            anyhow::bail!("No runs found in {}", journal_path.as_ref().display());
+        };
+
+        let entries = entries
+            .into_iter()
+            .filter(|entry| entry.run == run)
+            .collect::<Vec<_>>();
+        if entries.is_empty() {
+            anyhow::bail!("Run {run} not found in {}", journal_path.as_ref().display());
+        }
+        debug!("Undoing {} actions of run {run}", entries.len());
+
+        let mut num_reverted = 0;
+        for entry in entries.iter().rev() {
+            match revert(entry, commit).await {
+                Ok(()) => num_reverted += 1,
+                Err(e) => error!(
+                    "Cannot undo {} of {}: {e}",
+                    entry.action,
+                    entry.original.display()
+                ),
+            }
+        }
+        Ok((run, entries.len(), num_reverted))
+    }
+
+    /// Prints every run of the journal with its time and number of actions
+    pub async fn list<P: AsRef<Path>>(&self, journal_path: P) -> Result<()> {
+        let entries = journal::read_entries(journal_path).await?;
+        for (first, count) in runs(&entries) {
+            println!(
+                "{}\t{}\t{count} x {}",
+                first.run,
+                first.time.with_timezone(&chrono::Local).format("%F %T"),
+                first.action
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Groups consecutive `entries` by run, as the first entry of each run and its number of entries
+fn runs(entries: &[Entry]) -> Vec<(&Entry, usize)> {
+    let mut runs: Vec<(&Entry, usize)> = Vec::new();
+    for entry in entries {
+        match runs.last_mut() {
+            Some((first, count)) if first.run == entry.run => *count += 1,
+            _ => runs.push((entry, 1)),
+        }
+    }
+    runs
+}
+
+async fn revert(entry: &Entry, commit: bool) -> Result<()> {
+    let original = &entry.original;
+    match entry.action.as_str() {
+        "delete" => anyhow::bail!("deleted files cannot be restored"),
+        "reflink" => {
+            info!(
+                "{}: reflinks are independent copies already",
+                original.display()
+            );
+            Ok(())
+        }
+        "hardlink" | "symlink=absolute" | "symlink=relative" => {
+            let Some(survivor) = &entry.survivor else {
+                anyhow::bail!("no survivor recorded");
+            };
+            // Whatever else lives at `original` now was put there since, and the copy would lose it
+            let is_symlink = symlink_metadata(original).await?.is_symlink();
+            if is_symlink != entry.action.starts_with("symlink")
+                || !original.same_file(survivor).await?
+            {
+                anyhow::bail!(
+                    "it changed since and no longer links to {}",
+                    survivor.display()
+                );
+            }
+            original.replace_with_copy(survivor, commit).await
+        }
+        "trash" | "quarantine" => {
+            let Some(moved_to) = &entry.moved_to else {
+                anyhow::bail!("no destination recorded");
+            };
+            if !commit {
+                debug!(
+                    "{}: candidate for restoring from {}",
+                    original.display(),
+                    moved_to.display()
+                );
+                return Ok(());
+            }
+            moved_to.move_to(original).await?;
+            if entry.action == "trash" {
+                trash::forget(moved_to).await?;
+            }
+            if let Some(manifest) = &entry.manifest
+                && let Err(e) = action::unlist(manifest, original, &entry.chksum).await
+            {
+                warn!(
+                    "{} was restored but is still listed in {}: {e}",
+                    original.display(),
+                    manifest.display()
+                );
+            }
+            Ok(())
+        }
+        action => anyhow::bail!("unknown action {action}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::PathBuf;
+
+    fn entry(run: &str, action: &str, original: &Path, survivor: &Path) -> Entry {
+        Entry {
+            run: run.to_string(),
+            time: chrono::Utc::now(),
+            action: action.to_string(),
+            original: original.to_path_buf(),
+            survivor: Some(survivor.to_path_buf()),
+            size: 4,
+            chksum: "0123".to_string(),
+            reference: None,
+            moved_to: None,
+            manifest: None,
+        }
+    }
+
+    /// A survivor and the path a duplicate of it was found at, in a fresh directory
+    fn pair() -> (tempfile::TempDir, PathBuf, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let (survivor, original) = (dir.path().join("survivor"), dir.path().join("original"));
+        std::fs::write(&survivor, "same").unwrap();
+        (dir, survivor, original)
+    }
+
+    fn link(action: &str, survivor: &Path, original: &Path) {
+        match action {
+            "hardlink" => std::fs::hard_link(survivor, original).unwrap(),
+            _ => std::os::unix::fs::symlink(survivor, original).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn links_are_replaced_with_copies() {
+        for action in ["hardlink", "symlink=absolute", "symlink=relative"] {
+            let (_dir, survivor, original) = pair();
+            link(action, &survivor, &original);
+            let entry = entry("r", action, &original, &survivor);
+
+            revert(&entry, false).await.unwrap();
+            assert!(original.same_file(&survivor).await.unwrap(), "{action}");
+
+            revert(&entry, true).await.unwrap();
+            let md = symlink_metadata(&original).await.unwrap();
+            assert!(md.is_file(), "{action}");
+            assert!(!original.same_file(&survivor).await.unwrap(), "{action}");
+            assert_eq!(std::fs::read(&original).unwrap(), b"same", "{action}");
+            assert_eq!(std::fs::read(&survivor).unwrap(), b"same", "{action}");
+        }
+    }
+
+    #[tokio::test]
+    async fn links_changed_since_are_left_alone() {
+        for action in ["hardlink", "symlink=absolute"] {
+            let (_dir, survivor, original) = pair();
+            std::fs::write(&original, "new").unwrap();
+            let err = revert(&entry("r", action, &original, &survivor), true)
+                .await
+                .unwrap_err();
+            assert!(err.to_string().starts_with("it changed since"), "{err}");
+            assert_eq!(std::fs::read(&original).unwrap(), b"new", "{action}");
+        }
+
+        // A link of the other kind was not made by this action either
+        let (_dir, survivor, original) = pair();
+        link("symlink=absolute", &survivor, &original);
+        let entry = entry("r", "hardlink", &original, &survivor);
+        assert!(revert(&entry, true).await.is_err());
+        assert!(symlink_metadata(&original).await.unwrap().is_symlink());
+    }
+
+    #[tokio::test]
+    async fn deletes_cannot_be_undone_and_reflinks_need_not_be() {
+        let (_dir, survivor, original) = pair();
+        assert!(
+            revert(&entry("r", "delete", &original, &survivor), true)
+                .await
+                .is_err()
+        );
+        std::fs::write(&original, "same").unwrap();
+        revert(&entry("r", "reflink", &original, &survivor), true)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&original).unwrap(), b"same");
+    }
+
+    #[tokio::test]
+    async fn trashed_files_come_back_and_leave_no_info() {
+        let (dir, survivor, original) = pair();
+        let trash = dir.path().join("Trash");
+        std::fs::create_dir_all(trash.join("files")).unwrap();
+        std::fs::create_dir_all(trash.join("info")).unwrap();
+        let (moved_to, info) = (
+            trash.join("files/original"),
+            trash.join("info/original.trashinfo"),
+        );
+        std::fs::write(&moved_to, "same").unwrap();
+        std::fs::write(&info, "[Trash Info]\n").unwrap();
+        let entry = Entry {
+            moved_to: Some(moved_to.clone()),
+            ..entry("r", "trash", &original, &survivor)
+        };
+
+        revert(&entry, false).await.unwrap();
+        assert!(moved_to.exists() && !original.exists());
+        revert(&entry, true).await.unwrap();
+        assert_eq!(std::fs::read(&original).unwrap(), b"same");
+        assert!(!moved_to.exists() && !info.exists());
+    }
+
+    #[tokio::test]
+    async fn quarantined_files_come_back_unless_replaced() {
+        let (dir, survivor, original) = pair();
+        let moved_to = dir.path().join("q/tree/original");
+        std::fs::create_dir_all(moved_to.parent().unwrap()).unwrap();
+        std::fs::write(&moved_to, "same").unwrap();
+        let manifest = dir.path().join("q/manifest.tsv");
+        let canonical = |path: &Path| std::fs::canonicalize(path).unwrap();
+        let (listed, other) = (
+            format!(
+                "{}\t-\t4\t0123\t/",
+                canonical(dir.path()).join("original").display()
+            ),
+            format!("{}\t-\t4\t0123\t/", canonical(&survivor).display()),
+        );
+        let header = "original\tsurvivor\tsize\tchksum\troot";
+        std::fs::write(&manifest, format!("{header}\n{listed}\n{other}\n")).unwrap();
+        let entry = Entry {
+            moved_to: Some(moved_to.clone()),
+            manifest: Some(manifest.clone()),
+            ..entry("r", "quarantine", &original, &survivor)
+        };
+
+        std::fs::write(&original, "new").unwrap();
+        assert!(revert(&entry, true).await.is_err());
+        assert_eq!(std::fs::read(&original).unwrap(), b"new");
+        assert!(
+            std::fs::read_to_string(&manifest)
+                .unwrap()
+                .contains(&listed)
+        );
+
+        std::fs::remove_file(&original).unwrap();
+        revert(&entry, true).await.unwrap();
+        assert_eq!(std::fs::read(&original).unwrap(), b"same");
+        assert!(!moved_to.exists());
+        let rows = std::fs::read_to_string(&manifest).unwrap();
+        assert_eq!(rows, format!("{header}\n{other}\n"));
+    }
+
+    #[tokio::test]
+    async fn runs_are_undone_one_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let survivor = dir.path().join("survivor");
+        std::fs::write(&survivor, "same").unwrap();
+        let mut lines = String::new();
+        for (run, name) in [("r1", "a"), ("r1", "b"), ("r2", "c")] {
+            let original = dir.path().join(name);
+            link("hardlink", &survivor, &original);
+            let entry = entry(run, "hardlink", &original, &survivor);
+            lines.push_str(&serde_json::to_string(&entry).unwrap());
+            lines.push('\n');
+        }
+        let journal_path = dir.path().join("journal.jsonl");
+        std::fs::write(&journal_path, lines).unwrap();
+        let ino = |path: &Path| std::fs::metadata(path).unwrap().ino();
+        let linked = |name| ino(&dir.path().join(name)) == ino(&survivor);
+
+        let entries = journal::read_entries(&journal_path).await.unwrap();
+        let runs = runs(&entries)
+            .into_iter()
+            .map(|(first, count)| (first.run.as_str(), count))
+            .collect::<Vec<_>>();
+        assert_eq!(runs, [("r1", 2), ("r2", 1)]);
+
+        let latest = Undo {
+            run: None,
+            list: false,
+        };
+        let undone = latest.undo(&journal_path, true).await.unwrap();
+        assert_eq!(undone, ("r2".to_string(), 1, 1));
+        assert!(linked("a") && !linked("c"));
+
+        let first = Undo {
+            run: Some("r1".to_string()),
+            list: false,
+        };
+        let undone = first.undo(&journal_path, true).await.unwrap();
+        assert_eq!(undone, ("r1".to_string(), 2, 2));
+        assert!(!linked("a") && !linked("b"));
+
+        let missing = Undo {
+            run: Some("r3".to_string()),
+            list: false,
+        };
+        assert!(missing.undo(&journal_path, true).await.is_err());
+    }
+}